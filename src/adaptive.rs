@@ -0,0 +1,172 @@
+use crate::DownloadError;
+use reqwest::StatusCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How often the throughput is measured and the limit reconsidered.
+const WINDOW: Duration = Duration::from_secs(2);
+
+/// Limit on the number of chunks downloaded at once. A fixed limit never
+/// changes; an adaptive one starts low, adds a connection while that makes
+/// the download faster and halves when the server signals overload with
+/// resets, 429 or 503 answers.
+pub struct Connections {
+    semaphore: Arc<Semaphore>,
+    /// Permits to drop instead of returning, once the limit has been lowered
+    /// below the number of connections in use
+    debt: Arc<AtomicUsize>,
+    adaptive: Option<Mutex<Window>>,
+    max: usize,
+}
+
+/// Measurements since the limit was last reconsidered.
+struct Window {
+    limit: usize,
+    started: Instant,
+    bytes: u64,
+    errors: u64,
+    /// Throughput of the previous window, in bytes per second
+    previous: Option<f64>,
+    /// Whether the last change raised the limit
+    raised: bool,
+}
+
+impl Connections {
+    pub fn fixed(limit: usize) -> Self {
+        Connections {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            debt: Arc::new(AtomicUsize::new(0)),
+            adaptive: None,
+            max: limit,
+        }
+    }
+
+    /// Starts at a quarter of `max` connections, at least one, and never goes
+    /// beyond `max`.
+    pub fn adaptive(max: usize) -> Self {
+        let limit = (max / 4).max(1);
+        Connections {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            debt: Arc::new(AtomicUsize::new(0)),
+            adaptive: Some(Mutex::new(Window {
+                limit,
+                started: Instant::now(),
+                bytes: 0,
+                errors: 0,
+                previous: None,
+                raised: false,
+            })),
+            max,
+        }
+    }
+
+    /// Waits for a free connection.
+    pub async fn acquire(&self) -> ConnectionPermit {
+        let permit = self.semaphore.clone().acquire_owned().await.unwrap();
+        ConnectionPermit {
+            permit: Some(permit),
+            debt: self.debt.clone(),
+        }
+    }
+
+    /// Counts `bytes` received towards the current window.
+    pub fn record_bytes(&self, bytes: u64) {
+        if let Some(window) = &self.adaptive {
+            let mut window = window.lock().unwrap();
+            window.bytes += bytes;
+            self.reconsider(&mut window);
+        }
+    }
+
+    /// Counts a failed request if it suggests the server or network is
+    /// overloaded. Other errors, like 404, say nothing about concurrency.
+    pub fn record_error(&self, e: &DownloadError) {
+        let overload = match e {
+            DownloadError::Connect(_) | DownloadError::Request(_) => true,
+            DownloadError::Status(status) | DownloadError::RetryAfter { status, .. } => matches!(
+                *status,
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+            ),
+            _ => false,
+        };
+        if let (true, Some(window)) = (overload, &self.adaptive) {
+            let mut window = window.lock().unwrap();
+            window.errors += 1;
+            self.reconsider(&mut window);
+        }
+    }
+
+    /// Adjusts the limit once a window has passed: halve it after errors,
+    /// raise it while throughput keeps improving, and take back a raise that
+    /// made things slower.
+    fn reconsider(&self, window: &mut Window) {
+        let elapsed = window.started.elapsed();
+        // 出错时立即降低并发，不必等到窗口结束
+        if elapsed < WINDOW && window.errors == 0 {
+            return;
+        }
+        let rate = window.bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let old = window.limit;
+        if window.errors > 0 {
+            window.limit = (old / 2).max(1);
+            window.raised = false;
+        } else if window
+            .previous
+            .is_none_or(|previous| rate > previous * 1.05)
+        {
+            window.limit = (old + 1).min(self.max);
+            window.raised = window.limit > old;
+        } else if window.raised
+            && window
+                .previous
+                .is_some_and(|previous| rate < previous * 0.95)
+        {
+            window.limit = (old - 1).max(1);
+            window.raised = false;
+        }
+        if window.limit > old {
+            self.semaphore.add_permits(window.limit - old);
+        } else if window.limit < old {
+            // 空闲的名额直接收回，正在使用的等归还时再丢弃
+            let removed = self.semaphore.forget_permits(old - window.limit);
+            self.debt
+                .fetch_add(old - window.limit - removed, Ordering::SeqCst);
+        }
+        if window.limit != old {
+            log::debug!(
+                "{} connections ({}/s, {} errors)",
+                window.limit,
+                rate as u64,
+                window.errors
+            );
+        }
+        // 出错后重新开始计算吞吐量，降低并发前后的速度不具可比性
+        window.previous = (window.errors == 0).then_some(rate);
+        window.started = Instant::now();
+        window.bytes = 0;
+        window.errors = 0;
+    }
+}
+
+/// A connection slot, returned to the pool when dropped unless the limit has
+/// been lowered in the meantime.
+pub struct ConnectionPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    debt: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let owed = self
+            .debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                debt.checked_sub(1)
+            })
+            .is_ok();
+        if let (true, Some(permit)) = (owed, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{build_client, client_for, probe, DownloadError, DownloadOptions};
+use futures::StreamExt;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use std::time::{Duration, Instant};
+
+/// Connection counts tried by `benchmark`.
+pub const BENCHMARK_LEVELS: &[usize] = &[1, 2, 4, 8, 16, 32];
+
+/// Largest prefix of the file downloaded at each level.
+const SAMPLE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Smallest range one connection is given, so short samples are not split
+/// into requests that measure little more than latency.
+const MIN_RANGE: u64 = 64 * 1024;
+
+/// Throughput measured at one concurrency level.
+#[derive(Debug, Clone)]
+pub struct BenchmarkResult {
+    pub connections: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchmarkResult {
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Downloads the first few MiB of `url` with each of `BENCHMARK_LEVELS`
+/// parallel connections and reports how fast each level was. Nothing is
+/// written to disk. Levels that would give a connection less than 64 KiB
+/// are skipped.
+pub async fn benchmark(
+    url: &str,
+    opts: &DownloadOptions,
+) -> Result<Vec<BenchmarkResult>, DownloadError> {
+    let client = match &opts.client {
+        Some(client) => client.clone(),
+        None => build_client(opts)?,
+    };
+    let run = async {
+        let remote = probe::probe(
+            client_for(opts, &client, url.trim()),
+            url.trim(),
+            opts.timeout,
+        )
+        .await?;
+        let length = match remote.content_length {
+            Some(length) if remote.accept_ranges && length > 0 => length,
+            _ => return Err(DownloadError::RangesNotSupported),
+        };
+        let sample = length.min(SAMPLE_SIZE);
+        let mut results = Vec::new();
+        for &connections in BENCHMARK_LEVELS {
+            if connections > 1 && sample / (connections as u64) < MIN_RANGE {
+                break;
+            }
+            let started = Instant::now();
+            let range_size = sample / connections as u64;
+            let ranges = (0..connections as u64).map(|i| {
+                let start = i * range_size;
+                let end = if i == connections as u64 - 1 {
+                    sample - 1
+                } else {
+                    start + range_size - 1
+                };
+                fetch_range(
+                    client_for(opts, &client, &remote.url),
+                    &remote.url,
+                    start,
+                    end,
+                    opts.timeout,
+                )
+            });
+            let mut bytes = 0;
+            for result in futures::future::join_all(ranges).await {
+                bytes += result?;
+            }
+            let result = BenchmarkResult {
+                connections,
+                bytes,
+                elapsed: started.elapsed(),
+            };
+            log::debug!(
+                "{} connections: {} bytes in {:.2?}",
+                connections,
+                bytes,
+                result.elapsed
+            );
+            results.push(result);
+        }
+        Ok(results)
+    };
+    match &opts.cancel {
+        Some(cancel) => tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(DownloadError::Cancelled),
+            result = run => result,
+        },
+        None => run.await,
+    }
+}
+
+/// The result with the highest throughput. A level only wins over fewer
+/// connections if it is at least 10% faster, since extra connections cost
+/// the server more for little gain.
+pub fn best_level(results: &[BenchmarkResult]) -> Option<&BenchmarkResult> {
+    let mut best: Option<&BenchmarkResult> = None;
+    for result in results {
+        if best.is_none_or(|best| result.bytes_per_sec() > best.bytes_per_sec() * 1.1) {
+            best = Some(result);
+        }
+    }
+    best
+}
+
+/// Reads the inclusive range `start..=end` of `url` and throws the data away.
+/// Returns the number of bytes received.
+async fn fetch_range(
+    client: &reqwest::Client,
+    url: &str,
+    start: u64,
+    end: u64,
+    timeout: Option<Duration>,
+) -> Result<u64, DownloadError> {
+    let mut request = client
+        .get(url)
+        .header(RANGE, format!("bytes={}-{}", start, end));
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(DownloadError::Status(response.status()));
+    }
+    // 服务器返回整个文件时测得的不是分片下载的速度
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::RangeIgnored);
+    }
+    let mut bytes = 0;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        bytes += chunk?.len() as u64;
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,164 @@
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Digest algorithms a downloaded file can be checked with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+impl Algorithm {
+    /// Tells the algorithm of a hex digest from its length. 64 digits are
+    /// taken to be SHA-256, the more common of the two that long.
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(Algorithm::Md5),
+            40 => Some(Algorithm::Sha1),
+            64 => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// Incremental hasher for any of the supported algorithms.
+pub enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Returns the lowercase hex digest.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Md5(h) => hex(&h.finalize()),
+            Hasher::Sha1(h) => hex(&h.finalize()),
+            Hasher::Sha256(h) => hex(&h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Several digests of the same data, fed in order while it is downloaded so
+/// the file does not have to be read again afterwards.
+pub struct Digests {
+    hashers: Vec<(Algorithm, Hasher)>,
+    /// Number of bytes from the start of the file hashed so far
+    len: u64,
+}
+
+impl Digests {
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        let mut hashers: Vec<(Algorithm, Hasher)> = Vec::new();
+        for &algorithm in algorithms {
+            if !hashers.iter().any(|(a, _)| *a == algorithm) {
+                hashers.push((algorithm, Hasher::new(algorithm)));
+            }
+        }
+        Digests { hashers, len: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+        self.len += data.len() as u64;
+    }
+
+    /// Number of bytes hashed so far.
+    pub fn hashed(&self) -> u64 {
+        self.len
+    }
+
+    /// Starts over from the beginning of the file.
+    pub fn reset(&mut self) {
+        for (algorithm, hasher) in &mut self.hashers {
+            *hasher = Hasher::new(*algorithm);
+        }
+        self.len = 0;
+    }
+
+    /// Hashes `range` of the file at `path`, which must start where the data
+    /// hashed so far ends.
+    pub async fn update_from_file(&mut self, path: &Path, start: u64, end: u64) -> io::Result<()> {
+        debug_assert_eq!(start, self.len);
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut remaining = end - start + 1;
+        let mut buffer = vec![0; 64 * 1024];
+        while remaining > 0 {
+            let n = (remaining as usize).min(buffer.len());
+            file.read_exact(&mut buffer[..n]).await?;
+            self.update(&buffer[..n]);
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns the lowercase hex digest for each algorithm.
+    pub fn finalize(self) -> Vec<(Algorithm, String)> {
+        self.hashers
+            .into_iter()
+            .map(|(algorithm, hasher)| (algorithm, hasher.finalize()))
+            .collect()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Computes the lowercase hex digest of the file at `path`.
+pub async fn hash_file(path: &Path, algorithm: Algorithm) -> io::Result<String> {
+    // BLAKE3 可以把文件映射到内存后多线程计算，大文件快很多
+    if algorithm == Algorithm::Blake3 {
+        let path = path.to_path_buf();
+        return tokio::task::spawn_blocking(move || {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_mmap_rayon(&path)?;
+            Ok(hasher.finalize().to_hex().to_string())
+        })
+        .await
+        .map_err(io::Error::other)?;
+    }
+    let mut file = File::open(path).await?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize())
+}
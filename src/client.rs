@@ -0,0 +1,204 @@
+use crate::cookies::CookieJar;
+use crate::resolve::FamilyResolver;
+use crate::{DownloadError, DownloadOptions};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, Identity, NoProxy, Proxy, Url};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+#[cfg(not(any(feature = "rustls-tls", feature = "native-tls")))]
+compile_error!("enable either the `rustls-tls` or the `native-tls` feature");
+
+/// Builds the HTTP client shared by the HEAD request and all chunk requests,
+/// from the connection-level settings in `opts`.
+pub fn build_client(opts: &DownloadOptions) -> Result<reqwest::Client, DownloadError> {
+    build_client_with_jar(opts).map(|(client, _)| client)
+}
+
+/// Like [`build_client`], also returning the client's cookie jar so the
+/// cookies it collects can be saved afterwards.
+pub fn build_client_with_jar(
+    opts: &DownloadOptions,
+) -> Result<(reqwest::Client, Arc<CookieJar>), DownloadError> {
+    let jar = Arc::new(CookieJar::new(opts.cookies.clone()));
+    if let Some(path) = &opts.load_cookies {
+        jar.load_netscape(path)
+            .map_err(DownloadError::io("failed to read cookie file"))?;
+    }
+    let client = build_client_sharing_jar(opts, jar.clone())?;
+    Ok((client, jar))
+}
+
+/// Like [`build_client`], keeping cookies in the existing `jar` so several
+/// clients see the same cookies. `opts.cookies` and `opts.load_cookies` are
+/// left to whoever created the jar.
+pub fn build_client_sharing_jar(
+    opts: &DownloadOptions,
+    jar: Arc<CookieJar>,
+) -> Result<reqwest::Client, DownloadError> {
+    let mut headers = opts.headers.clone();
+    // 认证信息放在默认请求头中，reqwest 只会在同一主机的重定向中保留它
+    if let Some(user) = &opts.user {
+        let credentials = format!("{}:{}", user, opts.password.as_deref().unwrap_or(""));
+        let mut value = HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials)))
+            .map_err(|_| DownloadError::InvalidCredentials)?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    if let Some(token) = &opts.bearer {
+        if opts.user.is_some() {
+            return Err(DownloadError::ConflictingCredentials);
+        }
+        // 标记为敏感信息，避免令牌出现在调试输出中
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| DownloadError::InvalidCredentials)?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .default_headers(headers)
+        .user_agent(&opts.user_agent)
+        .cookie_provider(jar.clone());
+    // 按编译时启用的特性选择 TLS 后端，两者都启用时使用默认的 rustls
+    #[cfg(feature = "rustls-tls")]
+    {
+        builder = builder.use_rustls_tls();
+    }
+    #[cfg(all(feature = "native-tls", not(feature = "rustls-tls")))]
+    {
+        builder = builder.use_native_tls();
+    }
+    if let Some(timeout) = opts.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    let redirects = match opts.max_redirects {
+        0 => Policy::none(),
+        n => Policy::limited(n),
+    };
+    builder = builder.redirect(redirects);
+    if let Some(version) = opts.ip_version {
+        builder = builder.dns_resolver(Arc::new(FamilyResolver(version)));
+    }
+    // 端口设为 0 时 reqwest 使用 URL 中的端口
+    for (host, addrs) in &opts.resolve {
+        let addrs: Vec<SocketAddr> = addrs.iter().map(|&ip| SocketAddr::new(ip, 0)).collect();
+        builder = builder.resolve_to_addrs(host, &addrs);
+    }
+    if opts.insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    // 额外信任的根证书，与系统证书一起使用
+    for path in &opts.ca_certs {
+        let pem =
+            std::fs::read(path).map_err(DownloadError::io("failed to read CA certificate"))?;
+        let certs = Certificate::from_pem_bundle(&pem)
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .ok_or_else(|| DownloadError::InvalidCertificate(path.clone()))?;
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(cert) = &opts.client_cert {
+        builder = builder.identity(load_identity(cert, opts.client_key.as_deref())?);
+    }
+    // 未指定代理时 reqwest 会读取 HTTP_PROXY/HTTPS_PROXY/NO_PROXY 环境变量
+    if let Some(proxy) = &opts.proxy {
+        builder = builder.proxy(parse_proxy(proxy)?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Loads the client certificate chain and private key for mutual TLS.
+fn load_identity(cert: &Path, key: Option<&Path>) -> Result<Identity, DownloadError> {
+    let invalid = |_| DownloadError::InvalidClientCertificate(cert.to_path_buf());
+    let cert_pem =
+        std::fs::read(cert).map_err(DownloadError::io("failed to read client certificate"))?;
+    let key_pem = match key {
+        Some(key) => std::fs::read(key).map_err(DownloadError::io("failed to read client key"))?,
+        None => Vec::new(),
+    };
+    // rustls 需要证书和私钥在同一段 PEM 中，native-tls 则分开传入
+    #[cfg(feature = "rustls-tls")]
+    let identity = Identity::from_pem(&[cert_pem, key_pem].join(&b'\n')).map_err(invalid);
+    #[cfg(all(feature = "native-tls", not(feature = "rustls-tls")))]
+    let identity = if key.is_some() {
+        Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+    } else {
+        let (certs, key) = split_pem(&cert_pem);
+        Identity::from_pkcs8_pem(&certs, &key)
+    }
+    .map_err(invalid);
+    identity
+}
+
+/// Separates the certificates in a PEM file from the other blocks, such as a
+/// private key, for native-tls which wants them apart.
+#[cfg(all(feature = "native-tls", not(feature = "rustls-tls")))]
+fn split_pem(pem: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let (mut certs, mut rest) = (Vec::new(), Vec::new());
+    let mut in_cert = false;
+    for line in pem.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(b"-----BEGIN ") {
+            in_cert = line.starts_with(b"-----BEGIN CERTIFICATE-----");
+        }
+        if in_cert {
+            certs.extend_from_slice(line);
+        } else {
+            rest.extend_from_slice(line);
+        }
+    }
+    (certs, rest)
+}
+
+fn parse_proxy(proxy: &str) -> Result<Proxy, DownloadError> {
+    let invalid = || DownloadError::InvalidProxy(proxy.to_string());
+    let url = Url::parse(proxy).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(invalid());
+    }
+    let proxy = Proxy::all(url).map_err(|_| invalid())?;
+    Ok(proxy.no_proxy(NoProxy::from_env()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{serve, Response};
+
+    #[tokio::test]
+    async fn clients_sharing_a_jar_see_each_others_cookies() {
+        let server = serve(|request| match request.path.as_str() {
+            "/login" => Response::new(200).header("Set-Cookie", "session=abc; Path=/"),
+            _ => Response::new(200),
+        })
+        .await;
+        let opts = DownloadOptions::default();
+        let (client, jar) = build_client_with_jar(&opts).unwrap();
+        let with_credentials = DownloadOptions {
+            user: Some("user".to_string()),
+            ..opts.clone()
+        };
+        let other = build_client_sharing_jar(&with_credentials, jar).unwrap();
+
+        client
+            .get(format!("{}/login", server.url))
+            .send()
+            .await
+            .unwrap();
+        other
+            .get(format!("{}/file", server.url))
+            .send()
+            .await
+            .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests[1].header("cookie"), Some("session=abc"));
+        assert!(requests[1].header("authorization").is_some());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{parse_header, Args};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Defaults read from `config.toml`, applied to every flag that was not given
+/// on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    output: Option<PathBuf>,
+    max_chunks: Option<u64>,
+    max_retries: Option<u64>,
+    user_agent: Option<String>,
+    proxy: Option<String>,
+    /// Extra headers, `Name = "Value"`; `-H` replaces one of the same name
+    headers: BTreeMap<String, String>,
+}
+
+/// `idownloader/config.toml` in the user's config directory, e.g.
+/// `~/.config/idownloader/config.toml` on Linux.
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("idownloader").join("config.toml"))
+}
+
+/// Reads the config file at `path`, or the default one if it exists.
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+        Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+    };
+    toml::from_str(&text).map_err(|e| format!("invalid config file {}: {}", path.display(), e))
+}
+
+impl Config {
+    /// Fills in the fields of `args` that were not set on the command line.
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<(), String> {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if let Some(output) = self.output.filter(|_| !from_cli("output")) {
+            args.output = Some(output);
+        }
+        if let Some(max_chunks) = self.max_chunks.filter(|_| !from_cli("max_chunks")) {
+            if max_chunks == 0 {
+                return Err("max_chunks in the config file must be at least 1".to_string());
+            }
+            args.max_chunks = max_chunks;
+        }
+        if let Some(max_retries) = self.max_retries.filter(|_| !from_cli("max_retries")) {
+            args.max_retries = max_retries;
+        }
+        if let Some(user_agent) = self.user_agent.filter(|_| !from_cli("user_agent")) {
+            args.user_agent = user_agent;
+        }
+        if let Some(proxy) = self.proxy.filter(|_| !from_cli("proxy")) {
+            args.proxy = Some(proxy);
+        }
+        // 配置文件中的请求头放在前面，命令行给出的同名请求头覆盖它们
+        let mut headers = Vec::new();
+        for (name, value) in &self.headers {
+            let (name, value) = parse_header(&format!("{}: {}", name, value))?;
+            if !args.headers.iter().any(|(n, _)| *n == name) {
+                headers.push((name, value));
+            }
+        }
+        headers.append(&mut args.headers);
+        args.headers = headers;
+        Ok(())
+    }
+}
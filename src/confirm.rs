@@ -0,0 +1,45 @@
+use crate::{DownloadError, DownloadOptions, RemoteFile};
+use indicatif::HumanBytes;
+use std::io::{self, Write};
+use tokio::sync::Mutex;
+
+/// Held while a question is on screen, so downloads running at the same time
+/// ask one after another instead of all at once.
+static PROMPT: Mutex<()> = Mutex::const_new(());
+
+/// Asks on the terminal whether to go ahead when `remote` is bigger than
+/// `opts.confirm_size`. Anything but `y` or `yes` declines.
+pub(crate) async fn confirm_size(
+    opts: &DownloadOptions,
+    remote: &RemoteFile,
+) -> Result<(), DownloadError> {
+    let size = match (opts.confirm_size, remote.content_length) {
+        (Some(limit), Some(length)) if length > limit => length,
+        _ => return Ok(()),
+    };
+    let _prompt = PROMPT.lock().await;
+    let question = format!("{} is {}. Continue? [y/N] ", remote.url, HumanBytes(size));
+    let progress = opts.progress.clone();
+    // 等待输入会阻塞线程，放到阻塞线程池里；提问时暂停进度条，免得被覆盖
+    let answer = tokio::task::spawn_blocking(move || {
+        let ask = || {
+            eprint!("{}", question);
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            Ok::<_, io::Error>(answer)
+        };
+        match progress {
+            Some(progress) => progress.suspend(ask),
+            None => ask(),
+        }
+    })
+    .await
+    .map_err(io::Error::other)
+    .and_then(|answer| answer)
+    .map_err(DownloadError::io("failed to read the answer"))?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(DownloadError::Declined),
+    }
+}
@@ -0,0 +1,143 @@
+use cookie_store::{CookieDomain, CookieExpiration, CookieStore, RawCookie};
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+/// Cookie store for the client. Cookies set by the server (including during
+/// redirects) are kept for the following chunk requests, and cookies given on
+/// the command line are sent with every request.
+pub struct CookieJar {
+    store: Mutex<CookieStore>,
+    fixed: Vec<String>,
+}
+
+impl CookieJar {
+    pub fn new(fixed: Vec<String>) -> Self {
+        CookieJar {
+            store: Mutex::new(CookieStore::default()),
+            fixed,
+        }
+    }
+
+    /// Adds the cookies from a Netscape-format cookie file, as written by
+    /// curl, wget and most browser export tools.
+    pub fn load_netscape(&self, path: &Path) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        let mut store = self.store.lock().unwrap();
+        for line in text.lines() {
+            // curl 用 #HttpOnly_ 前缀标记 HttpOnly 的 cookie，其余 # 开头的是注释
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(rest) => (rest, true),
+                None if line.starts_with('#') => continue,
+                None => (line, false),
+            };
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, include_subdomains, path, secure, expires, name, value] = fields[..]
+            else {
+                continue;
+            };
+            let secure = secure.eq_ignore_ascii_case("TRUE");
+            let host = domain.trim_start_matches('.');
+            let scheme = if secure { "https" } else { "http" };
+            let Ok(url) = Url::parse(&format!("{}://{}{}", scheme, host, path)) else {
+                continue;
+            };
+
+            let mut cookie = RawCookie::build((name.to_string(), value.to_string()))
+                .path(path.to_string())
+                .secure(secure)
+                .http_only(http_only);
+            if include_subdomains.eq_ignore_ascii_case("TRUE") {
+                cookie = cookie.domain(host.to_string());
+            }
+            // 过期时间为 0 表示会话 cookie
+            if let Some(expires) = expires
+                .parse::<i64>()
+                .ok()
+                .filter(|&t| t > 0)
+                .and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok())
+            {
+                cookie = cookie.expires(expires);
+            }
+            // 已过期或与域名不匹配的条目直接忽略
+            let _ = store.insert_raw(&cookie.build(), &url);
+        }
+        Ok(())
+    }
+
+    /// Writes every unexpired cookie in the store to `path` in Netscape
+    /// format, so it can be passed to `load_netscape` again. Session cookies
+    /// are written with an expiry of 0, like curl does. Cookies given on the
+    /// command line are not part of the store and are not written.
+    pub fn save_netscape(&self, path: &Path) -> io::Result<()> {
+        let store = self.store.lock().unwrap();
+        // 按域名、路径和名称排序，多次保存的结果保持稳定
+        let mut cookies: Vec<_> = store.iter_unexpired().collect();
+        cookies.sort_by(|a, b| {
+            (a.domain.as_cow(), &*a.path, a.name()).cmp(&(b.domain.as_cow(), &*b.path, b.name()))
+        });
+        let mut text = String::from("# Netscape HTTP Cookie File\n");
+        for cookie in cookies {
+            let (domain, include_subdomains) = match &cookie.domain {
+                CookieDomain::HostOnly(host) => (host.clone(), "FALSE"),
+                CookieDomain::Suffix(suffix) => (format!(".{}", suffix), "TRUE"),
+                CookieDomain::NotPresent | CookieDomain::Empty => continue,
+            };
+            let expires = match cookie.expires {
+                CookieExpiration::AtUtc(at) => at.unix_timestamp().max(0),
+                CookieExpiration::SessionEnd => 0,
+            };
+            let prefix = if cookie.http_only() == Some(true) {
+                "#HttpOnly_"
+            } else {
+                ""
+            };
+            let secure = if cookie.secure() == Some(true) {
+                "TRUE"
+            } else {
+                "FALSE"
+            };
+            text.push_str(&format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                prefix,
+                domain,
+                include_subdomains,
+                &*cookie.path,
+                secure,
+                expires,
+                cookie.name(),
+                cookie.value()
+            ));
+        }
+        fs::write(path, text)
+    }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| RawCookie::parse(v.to_string()).ok());
+        self.store
+            .lock()
+            .unwrap()
+            .store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let store = self.store.lock().unwrap();
+        let pairs: Vec<String> = store
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .chain(self.fixed.iter().cloned())
+            .collect();
+        if pairs.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&pairs.join("; ")).ok()
+    }
+}
@@ -0,0 +1,29 @@
+use crate::DownloadError;
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder};
+use futures::TryStreamExt;
+use reqwest::header::CONTENT_ENCODING;
+use std::io;
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+
+/// Reads the body of `response`, undoing its Content-Encoding so that what
+/// comes out is the file itself rather than its compressed form.
+pub fn body_reader(
+    response: reqwest::Response,
+) -> Result<Pin<Box<dyn AsyncRead + Send>>, DownloadError> {
+    let encoding = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase());
+    let reader = StreamReader::new(response.bytes_stream().map_err(io::Error::other));
+    Ok(match encoding.as_deref() {
+        None | Some("") | Some("identity") => Box::pin(reader),
+        Some("gzip") | Some("x-gzip") => Box::pin(GzipDecoder::new(reader)),
+        // HTTP 的 deflate 实际上是 zlib 格式
+        Some("deflate") => Box::pin(ZlibDecoder::new(reader)),
+        Some("br") => Box::pin(BrotliDecoder::new(reader)),
+        Some(other) => return Err(DownloadError::UnsupportedEncoding(other.to_string())),
+    })
+}
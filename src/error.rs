@@ -0,0 +1,234 @@
+use reqwest::StatusCode;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("failed to connect to host")]
+    Connect(#[source] reqwest::Error),
+
+    #[error("too many redirects")]
+    TooManyRedirects(#[source] reqwest::Error),
+
+    #[error("request failed: {0}")]
+    Request(#[source] reqwest::Error),
+
+    #[error("server returned status code {0}")]
+    Status(StatusCode),
+
+    #[error("server returned status code {status}, retry after {}s", .retry_after.as_secs())]
+    RetryAfter {
+        status: StatusCode,
+        retry_after: Duration,
+    },
+
+    #[error("server rejected range {start}-{end}, the file may have changed on the server")]
+    RangeNotSatisfiable { start: u64, end: u64 },
+
+    #[error("server ignored the range request")]
+    RangeIgnored,
+
+    #[error("server does not support range requests or did not report the file size")]
+    RangesNotSupported,
+
+    #[error(
+        "server sent bytes {}-{} instead of {}-{}",
+        .actual.0, .actual.1, .expected.0, .expected.1
+    )]
+    UnexpectedRange {
+        expected: (u64, u64),
+        actual: (u64, u64),
+    },
+
+    #[error(
+        "mirror {url} serves a different file: expected {expected} bytes, it reports {}",
+        .actual.map_or("no size".to_string(), |n| format!("{} bytes", n))
+    )]
+    MirrorMismatch {
+        url: String,
+        expected: u64,
+        actual: Option<u64>,
+    },
+
+    #[error("{0} is not a local file")]
+    InvalidFileUrl(String),
+
+    #[error("invalid metalink file: {0}")]
+    InvalidMetalink(String),
+
+    #[error("invalid manifest file: {0}")]
+    InvalidManifest(String),
+
+    #[error("server sent an HTML page instead of the file, it may be an error or login page")]
+    HtmlResponse,
+
+    #[error("expected content type {expected}, server sent {actual}")]
+    UnexpectedContentType { expected: String, actual: String },
+
+    #[error("unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+
+    #[error("invalid content length: {0}")]
+    InvalidContentLength(String),
+
+    #[error("credentials contain characters that cannot be sent in a header")]
+    InvalidCredentials,
+
+    #[error("basic and bearer authentication cannot be used together")]
+    ConflictingCredentials,
+
+    #[error("invalid proxy URL: {0}")]
+    InvalidProxy(String),
+
+    #[error("no valid PEM certificates in {}", .0.display())]
+    InvalidCertificate(PathBuf),
+
+    #[error("failed to load the client certificate or key from {}", .0.display())]
+    InvalidClientCertificate(PathBuf),
+
+    #[error("failed to determine the download directory")]
+    NoDownloadDir,
+
+    #[error("{} already exists, use --force to overwrite it", .0.display())]
+    FileExists(PathBuf),
+
+    #[error("{context}: {source}")]
+    Io {
+        context: &'static str,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(
+        "not enough disk space in {}: {needed} bytes needed, {available} available",
+        .path.display()
+    )]
+    InsufficientSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+
+    #[error("size mismatch: expected {expected} bytes, got {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+
+    #[error("checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error("no checksum file found for {0}")]
+    SidecarNotFound(String),
+
+    #[error("checksum file {0} has no digest for this file")]
+    InvalidSidecar(String),
+
+    #[error("download cancelled")]
+    Cancelled,
+
+    #[error("download declined")]
+    Declined,
+
+    #[error("download quota of {0} bytes used up")]
+    QuotaExceeded(u64),
+
+    #[error("file is larger than the size limit of {0} bytes")]
+    TooLarge(u64),
+
+    #[error(
+        "download incomplete: {} of {total} chunks failed, run again to resume",
+        .failed.len()
+    )]
+    Incomplete {
+        failed: Vec<FailedChunk>,
+        total: u64,
+    },
+}
+
+/// A chunk that could not be downloaded, with its inclusive byte range and
+/// the error of its last attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedChunk {
+    pub index: u64,
+    pub start: u64,
+    pub end: u64,
+    /// `None` if the chunk was never attempted, e.g. after the quota ran out
+    pub error: Option<String>,
+}
+
+impl DownloadError {
+    /// How long the server asked us to wait before trying again, if it did.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            DownloadError::RetryAfter { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// Whether trying the same request again may succeed. Client errors
+    /// other than 408 and 429 will not go away by retrying, and neither will a
+    /// server that rejects or ignores ranges or a full disk; timeouts, network
+    /// errors and 5xx answers might.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::Status(status) | DownloadError::RetryAfter { status, .. } => {
+                !status.is_client_error()
+                    || matches!(
+                        *status,
+                        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                    )
+            }
+            DownloadError::RangeNotSatisfiable { .. }
+            | DownloadError::RangeIgnored
+            | DownloadError::TooLarge(_) => false,
+            _ => !self.is_disk_full(),
+        }
+    }
+
+    /// Whether the download failed because the disk ran out of space.
+    pub fn is_disk_full(&self) -> bool {
+        match self {
+            DownloadError::InsufficientSpace { .. } => true,
+            DownloadError::Io { source, .. } => source.kind() == io::ErrorKind::StorageFull,
+            _ => false,
+        }
+    }
+
+    /// Whether the request timed out, before or while reading the body.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            DownloadError::Request(e) => e.is_timeout(),
+            // 读取响应体时的错误被包装在 io::Error 里
+            DownloadError::Io { source, .. } => source
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<reqwest::Error>())
+                .is_some_and(reqwest::Error::is_timeout),
+            _ => false,
+        }
+    }
+
+    /// The chunks that failed, for an `Incomplete` download.
+    pub fn failed_chunks(&self) -> &[FailedChunk] {
+        match self {
+            DownloadError::Incomplete { failed, .. } => failed,
+            _ => &[],
+        }
+    }
+
+    /// Wraps an I/O error with a short description of what was being done,
+    /// for use with `map_err`.
+    pub fn io(context: &'static str) -> impl FnOnce(io::Error) -> DownloadError {
+        move |source| DownloadError::Io { context, source }
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_connect() {
+            DownloadError::Connect(e)
+        } else if e.is_redirect() {
+            DownloadError::TooManyRedirects(e)
+        } else {
+            DownloadError::Request(e)
+        }
+    }
+}
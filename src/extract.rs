@@ -0,0 +1,351 @@
+use flate2::read::GzDecoder;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path};
+use xz2::read::XzDecoder;
+
+/// Archive formats `--extract` can unpack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+}
+
+/// How many entries were written, and how many were left out because their
+/// path or link target would land outside the destination.
+#[derive(Debug, Default)]
+pub struct Extracted {
+    pub entries: usize,
+    pub skipped: usize,
+}
+
+impl Format {
+    /// Detects the format from the file name, or from the first bytes of the
+    /// file when the name has no known extension. `None` if it is not an
+    /// archive, including a `.gz` or `.xz` that does not hold a tarball.
+    pub fn detect(path: &Path) -> io::Result<Option<Self>> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let by_name = [
+            (".zip", Format::Zip),
+            (".tar", Format::Tar),
+            (".tar.gz", Format::TarGz),
+            (".tgz", Format::TarGz),
+            (".tar.xz", Format::TarXz),
+            (".txz", Format::TarXz),
+        ];
+        if let Some(&(_, format)) = by_name.iter().find(|(ext, _)| name.ends_with(ext)) {
+            return Ok(Some(format));
+        }
+        // 下载链接常常没有扩展名，这时看文件头
+        let header = read_header(File::open(path)?)?;
+        Ok(if header.starts_with(b"PK\x03\x04") {
+            Some(Format::Zip)
+        } else if is_tar(&header) {
+            Some(Format::Tar)
+        } else if header.starts_with(&[0x1f, 0x8b])
+            && is_tar(&read_header(GzDecoder::new(File::open(path)?))?)
+        {
+            Some(Format::TarGz)
+        } else if header.starts_with(b"\xfd7zXZ\0")
+            && is_tar(&read_header(XzDecoder::new(File::open(path)?))?)
+        {
+            Some(Format::TarXz)
+        } else {
+            None
+        })
+    }
+}
+
+/// The first 512 bytes of `reader`, or fewer if it is shorter.
+fn read_header(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    reader.take(512).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// POSIX and GNU tar headers carry "ustar" at offset 257.
+fn is_tar(header: &[u8]) -> bool {
+    header.get(257..262) == Some(b"ustar")
+}
+
+/// Unpacks the archive at `path` into `dest`, creating it if needed.
+/// Entries with `..` components, absolute zip paths and links that point
+/// outside `dest` are skipped, so a malicious archive cannot write outside
+/// `dest` (zip slip). Absolute tar paths are unpacked below `dest`.
+pub fn extract(path: &Path, format: Format, dest: &Path) -> io::Result<Extracted> {
+    fs::create_dir_all(dest)?;
+    let file = BufReader::new(File::open(path)?);
+    match format {
+        Format::Zip => extract_zip(file, dest),
+        Format::Tar => extract_tar(file, dest),
+        Format::TarGz => extract_tar(GzDecoder::new(file), dest),
+        Format::TarXz => extract_tar(XzDecoder::new(file), dest),
+    }
+}
+
+fn extract_zip(file: BufReader<File>, dest: &Path) -> io::Result<Extracted> {
+    let mut archive = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+    let mut extracted = Extracted::default();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(io::Error::other)?;
+        // 符号链接可能指向目标目录以外，后面的条目会顺着它写出去，一律跳过
+        let name = match entry.enclosed_name() {
+            Some(name) if !entry.is_symlink() => name,
+            _ => {
+                extracted.skipped += 1;
+                continue;
+            }
+        };
+        let target = dest.join(name);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            io::copy(&mut entry, &mut File::create(&target)?)?;
+            #[cfg(unix)]
+            if let Some(mode) = entry.unix_mode() {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&target, fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+        extracted.entries += 1;
+    }
+    Ok(extracted)
+}
+
+fn extract_tar(reader: impl Read, dest: &Path) -> io::Result<Extracted> {
+    let mut archive = tar::Archive::new(reader);
+    let mut extracted = Extracted::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // 指向 dest 以外的链接不解出，否则之后的条目会顺着它写出去
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let inside = match (entry.path(), entry.link_name()) {
+                (Ok(path), Ok(Some(target))) => {
+                    link_stays_inside(&path, &target, entry_type.is_symlink())
+                }
+                _ => false,
+            };
+            if !inside {
+                extracted.skipped += 1;
+                continue;
+            }
+        }
+        // unpack_in 拒绝写到 dest 以外的条目，包括经由之前解出的符号链接
+        if entry.unpack_in(dest)? {
+            extracted.entries += 1;
+        } else {
+            extracted.skipped += 1;
+        }
+    }
+    Ok(extracted)
+}
+
+/// Whether the link `target` of the entry at `path` stays inside the
+/// destination. Symbolic links are resolved from the entry's directory,
+/// hard links from the top of the archive.
+fn link_stays_inside(path: &Path, target: &Path, symbolic: bool) -> bool {
+    let mut depth = if symbolic {
+        path.components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .count()
+            .saturating_sub(1)
+    } else {
+        0
+    };
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tar::{EntryType, Header};
+    use zip::write::SimpleFileOptions;
+
+    /// A directory holding the archive, with `out` as the destination inside
+    /// it, so anything written next to `out` has escaped.
+    struct Dirs {
+        root: tempfile::TempDir,
+    }
+
+    impl Dirs {
+        fn new() -> Self {
+            Dirs {
+                root: tempfile::tempdir().unwrap(),
+            }
+        }
+
+        fn path(&self, name: &str) -> std::path::PathBuf {
+            self.root.path().join(name)
+        }
+
+        fn dest(&self) -> std::path::PathBuf {
+            self.path("out")
+        }
+
+        /// Asserts that only `archive` and `out` exist next to each other.
+        fn assert_nothing_escaped(&self, archive: &str) {
+            let mut names: Vec<_> = fs::read_dir(self.root.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            assert_eq!(names, [archive, "out"]);
+        }
+    }
+
+    /// Appends an entry with the raw `name`, which tar::Builder would refuse
+    /// when it contains `..`.
+    fn tar_entry(
+        builder: &mut tar::Builder<File>,
+        name: &str,
+        entry_type: EntryType,
+        link: &str,
+        data: &[u8],
+    ) {
+        let mut header = Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    #[test]
+    fn zip_entries_cannot_leave_the_destination() {
+        let dirs = Dirs::new();
+        let absolute = dirs.path("absolute.txt");
+        let mut zip = zip::ZipWriter::new(File::create(dirs.path("evil.zip")).unwrap());
+        let options = SimpleFileOptions::default();
+        for name in [
+            "../evil.txt",
+            "a/../../evil.txt",
+            &absolute.to_string_lossy(),
+            "good/file.txt",
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(b"data").unwrap();
+        }
+        zip.add_symlink("link", "..", options).unwrap();
+        zip.add_symlink("abs-link", dirs.root.path().to_string_lossy(), options)
+            .unwrap();
+        zip.start_file("link/evil.txt", options).unwrap();
+        zip.write_all(b"data").unwrap();
+        zip.finish().unwrap();
+
+        let extracted = extract(&dirs.path("evil.zip"), Format::Zip, &dirs.dest()).unwrap();
+        dirs.assert_nothing_escaped("evil.zip");
+        assert_eq!(
+            fs::read(dirs.dest().join("good/file.txt")).unwrap(),
+            b"data"
+        );
+        assert!(!dirs.dest().join("link").is_symlink());
+        assert!(!dirs.dest().join("abs-link").exists());
+        assert!(extracted.skipped >= 4, "{:?}", extracted);
+    }
+
+    #[test]
+    fn tar_entries_cannot_leave_the_destination() {
+        let dirs = Dirs::new();
+        let mut tar = tar::Builder::new(File::create(dirs.path("evil.tar")).unwrap());
+        let root = dirs.root.path().to_string_lossy().into_owned();
+        tar_entry(&mut tar, "../evil.txt", EntryType::Regular, "", b"data");
+        tar_entry(
+            &mut tar,
+            "a/../../evil.txt",
+            EntryType::Regular,
+            "",
+            b"data",
+        );
+        tar_entry(&mut tar, "link", EntryType::Symlink, "..", b"");
+        tar_entry(&mut tar, "link/evil.txt", EntryType::Regular, "", b"data");
+        tar_entry(&mut tar, "abs-link", EntryType::Symlink, &root, b"");
+        tar_entry(
+            &mut tar,
+            "abs-link/evil.txt",
+            EntryType::Regular,
+            "",
+            b"data",
+        );
+        tar_entry(&mut tar, "hard", EntryType::Link, "../evil.txt", b"");
+        tar_entry(&mut tar, "good/file.txt", EntryType::Regular, "", b"data");
+        tar_entry(
+            &mut tar,
+            "good/same.txt",
+            EntryType::Symlink,
+            "file.txt",
+            b"",
+        );
+        tar.finish().unwrap();
+        drop(tar);
+
+        let extracted = extract(&dirs.path("evil.tar"), Format::Tar, &dirs.dest()).unwrap();
+        dirs.assert_nothing_escaped("evil.tar");
+        assert_eq!(
+            fs::read(dirs.dest().join("good/file.txt")).unwrap(),
+            b"data"
+        );
+        assert!(!dirs.dest().join("link").is_symlink());
+        assert!(!dirs.dest().join("abs-link").is_symlink());
+        assert!(!dirs.dest().join("hard").exists());
+        assert_eq!(extracted.skipped, 5, "{:?}", extracted);
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read(dirs.dest().join("good/same.txt")).unwrap(),
+            b"data"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn absolute_tar_paths_land_inside_the_destination() {
+        let dirs = Dirs::new();
+        let mut tar = tar::Builder::new(File::create(dirs.path("abs.tar")).unwrap());
+        let absolute = dirs.path("absolute.txt").to_string_lossy().into_owned();
+        tar_entry(&mut tar, &absolute, EntryType::Regular, "", b"data");
+        tar.finish().unwrap();
+        drop(tar);
+
+        extract(&dirs.path("abs.tar"), Format::Tar, &dirs.dest()).unwrap();
+        dirs.assert_nothing_escaped("abs.tar");
+        // tar 去掉开头的 /，放在 dest 下面
+        assert!(dirs.dest().join(absolute.trim_start_matches('/')).exists());
+    }
+
+    #[test]
+    fn links_inside_the_archive_are_allowed() {
+        assert!(link_stays_inside(Path::new("a/b"), Path::new("../c"), true));
+        assert!(link_stays_inside(Path::new("a/b"), Path::new("./c"), true));
+        assert!(!link_stays_inside(
+            Path::new("a/b"),
+            Path::new("../../c"),
+            true
+        ));
+        assert!(!link_stays_inside(Path::new("a"), Path::new("/etc"), true));
+        assert!(link_stays_inside(Path::new("a/b"), Path::new("c/d"), false));
+        assert!(!link_stays_inside(
+            Path::new("a/b"),
+            Path::new("../c"),
+            false
+        ));
+    }
+}
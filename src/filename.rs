@@ -0,0 +1,537 @@
+use percent_encoding::percent_decode;
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+
+/// Picks a local file name for a download, preferring the server's
+/// Content-Disposition header and falling back to the URL path. Names taken
+/// from the URL are reduced to ASCII letters, digits and `-`, which every
+/// file system accepts.
+pub fn extract_filename(url: &str, headers: &reqwest::header::HeaderMap) -> String {
+    filename_for(url, headers, false)
+}
+
+/// Like `extract_filename`, but names taken from the URL keep any Unicode
+/// text, e.g. `文档.pdf`. Only path separators, control characters and
+/// characters Windows reserves are replaced.
+pub fn extract_unicode_filename(url: &str, headers: &reqwest::header::HeaderMap) -> String {
+    filename_for(url, headers, true)
+}
+
+fn filename_for(url: &str, headers: &reqwest::header::HeaderMap, unicode: bool) -> String {
+    let name = server_filename(url, headers, unicode);
+    // 其他系统上这些名字都能创建，不必改动
+    if cfg!(windows) {
+        windows_filename(&name)
+    } else {
+        name
+    }
+}
+
+fn server_filename(url: &str, headers: &reqwest::header::HeaderMap, unicode: bool) -> String {
+    // 首先尝试从 Content-Disposition 头中获取文件名
+    if let Some(content_disposition) = headers.get("content-disposition") {
+        if let Ok(content_disposition_str) = content_disposition.to_str() {
+            // filename* 可以携带非 ASCII 文件名，存在时优先使用
+            let extended =
+                disposition_param(content_disposition_str, "filename*").and_then(decode_ext_value);
+            let plain = disposition_param(content_disposition_str, "filename").map(unquote);
+            if let Some(filename) = extended
+                .or(plain)
+                .and_then(|f| sanitize_header_filename(&f))
+            {
+                return filename;
+            }
+        }
+    }
+
+    // 如果没有找到，再从URL路径中提取文件名，查询参数和片段不参与
+    let parsed = Url::parse(url).ok();
+    let path = parsed.as_ref().map(|u| u.path());
+
+    let (base, ext) = path
+        .map(|p| {
+            let path = Path::new(p);
+            let stem = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("bin");
+            (stem, ext)
+        })
+        .unwrap_or_else(|| ("download", "bin"));
+
+    let host_name = parsed
+        .as_ref()
+        .and_then(|u| u.host_str())
+        .unwrap_or("download");
+
+    // 扩展名同样可能带有编码字符，无法得到干净的扩展名时退回 bin
+    let ext = percent_decode(ext.as_bytes()).decode_utf8_lossy();
+    let clean_ext = |c: char| {
+        if unicode {
+            c.is_alphanumeric()
+        } else {
+            c.is_ascii_alphanumeric()
+        }
+    };
+    let mut ext = if !ext.is_empty() && ext.chars().all(clean_ext) {
+        ext.to_string()
+    } else {
+        "bin".to_string()
+    };
+    // .tar.gz 这类双重扩展名要整体保留，否则 .tar 会被当作文件名的一部分替换掉
+    let mut base = base;
+    if matches!(
+        ext.to_ascii_lowercase().as_str(),
+        "gz" | "bz2" | "xz" | "zst"
+    ) {
+        let tar = base.len().checked_sub(4).filter(|&i| {
+            base.get(i..)
+                .is_some_and(|suffix| suffix.eq_ignore_ascii_case(".tar"))
+        });
+        if let Some(i) = tar {
+            ext = format!("{}.{}", &base[i + 1..], ext);
+            base = &base[..i];
+        }
+    }
+
+    let decoded = percent_decode(base.as_bytes()).decode_utf8_lossy();
+    let safe_name = if unicode {
+        // 结尾的点和空格在 Windows 上会被悄悄去掉，一并去掉
+        decoded
+            .replace(|c: char| c.is_control() || r#"/\<>:"|?*"#.contains(c), "_")
+            .trim_start()
+            .trim_end_matches(['.', ' ', '_'])
+            .to_string()
+    } else {
+        decoded
+            .replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
+            .trim_end_matches('_')
+            .to_string()
+    };
+
+    if safe_name.is_empty() {
+        format!("{}.{}", host_name.replace('.', "_"), ext)
+    } else {
+        format!("{}.{}", safe_name, ext)
+    }
+}
+
+/// The directories of `url` as a relative path, like `wget -x`: the host
+/// (with `_port` when one is given), then the path up to the file name with
+/// the first `cut_dirs` directories left out. `.` and `..` segments are
+/// dropped so the result never leaves the output directory.
+pub fn url_directories(url: &str, cut_dirs: usize) -> PathBuf {
+    let mut dirs = PathBuf::new();
+    let Ok(url) = Url::parse(url) else {
+        return dirs;
+    };
+    let mut push = |name: &str| {
+        if cfg!(windows) {
+            dirs.push(windows_filename(name));
+        } else {
+            dirs.push(name);
+        }
+    };
+    // IPv6 主机名带有冒号，和路径一样要处理成合法的目录名
+    if let Some(host) = url.host_str() {
+        match url.port() {
+            Some(port) => push(&format!("{}_{}", host, port)),
+            None => push(host),
+        }
+    }
+    let mut segments: Vec<_> = url.path_segments().into_iter().flatten().collect();
+    // 最后一段是文件名
+    segments.pop();
+    for segment in segments.into_iter().skip(cut_dirs) {
+        let segment = percent_decode(segment.as_bytes()).decode_utf8_lossy();
+        let segment = segment.replace(|c: char| c.is_control() || c == '/' || c == '\\', "_");
+        if segment.is_empty() || segment == "." || segment == ".." {
+            continue;
+        }
+        push(&segment);
+    }
+    dirs
+}
+
+/// Device names Windows reserves in every directory, with or without an
+/// extension.
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes `name` valid on Windows: characters it forbids become `_`,
+/// trailing dots and spaces are dropped, and reserved device names such as
+/// `nul.txt` get a `_` prefix.
+fn windows_filename(name: &str) -> String {
+    let name = name.replace(|c: char| c.is_control() || r#"<>:"/\|?*"#.contains(c), "_");
+    let name = name.trim_end_matches(['.', ' ']);
+    // NUL、nul.txt、nul.tar.gz 都指向设备，只看第一个点之前的部分
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if name.is_empty() {
+        "download.bin".to_string()
+    } else if WINDOWS_RESERVED
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Inserts ` (n)` before the extension, e.g. `report (2).pdf`. Double
+/// extensions such as `.tar.gz` stay together.
+pub fn numbered_filename(filename: &str, n: u32) -> String {
+    let lower = filename.to_ascii_lowercase();
+    let split = [".tar.gz", ".tar.bz2", ".tar.xz", ".tar.zst"]
+        .iter()
+        .find(|ext| lower.len() > ext.len() && lower.ends_with(*ext))
+        .map(|ext| filename.len() - ext.len())
+        .or_else(|| filename.rfind('.').filter(|&i| i > 0))
+        .unwrap_or(filename.len());
+    format!("{} ({}){}", &filename[..split], n, &filename[split..])
+}
+
+/// Finds the value of parameter `name` in a Content-Disposition header.
+/// Semicolons inside quoted values do not split parameters.
+fn disposition_param<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    let mut params = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in header.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(&header[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&header[start..]);
+
+    params.into_iter().find_map(|part| {
+        let (key, value) = part.split_once('=')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Strips surrounding double quotes from a parameter value and resolves
+/// backslash escapes inside them, as allowed by RFC 6266.
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return value.to_string(),
+    };
+    let mut unescaped = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                unescaped.push(next);
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+/// Makes a server-supplied file name safe to create in the output directory:
+/// only the last path component is kept and control characters are dropped.
+fn sanitize_header_filename(filename: &str) -> Option<String> {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Decodes an RFC 5987 extended value such as `UTF-8''%E6%96%87.pdf`.
+/// Returns `None` for unsupported charsets or malformed values.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let bytes: Vec<u8> = percent_decode(parts.next()?.as_bytes()).collect();
+    let decoded = if charset.eq_ignore_ascii_case("UTF-8") {
+        String::from_utf8(bytes).ok()?
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        bytes.into_iter().map(char::from).collect()
+    } else {
+        return None;
+    };
+    (!decoded.is_empty()).then_some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderMap, HeaderValue, CONTENT_DISPOSITION};
+
+    fn disposition(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_DISPOSITION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn decodes_utf8_extended_filename() {
+        let headers = disposition("attachment; filename*=UTF-8''%E6%96%87%E4%BB%B6.pdf");
+        assert_eq!(
+            extract_filename("https://example.com/download", &headers),
+            "文件.pdf"
+        );
+    }
+
+    #[test]
+    fn prefers_extended_filename_over_plain() {
+        let headers = disposition(
+            "attachment; filename=\"fallback.pdf\"; filename*=UTF-8''%E6%96%87%E4%BB%B6.pdf",
+        );
+        assert_eq!(
+            extract_filename("https://example.com/download", &headers),
+            "文件.pdf"
+        );
+    }
+
+    #[test]
+    fn decode_ext_value_handles_charsets() {
+        assert_eq!(
+            decode_ext_value("utf-8'en'%E6%96%87.pdf").as_deref(),
+            Some("文.pdf")
+        );
+        assert_eq!(
+            decode_ext_value("ISO-8859-1''caf%E9.txt").as_deref(),
+            Some("café.txt")
+        );
+        assert_eq!(decode_ext_value("KOI8-R''%E6.txt"), None);
+    }
+
+    #[test]
+    fn decode_ext_value_rejects_malformed_values() {
+        assert_eq!(decode_ext_value("%E6%96%87.pdf"), None);
+        assert_eq!(decode_ext_value("UTF-8'%E6.pdf"), None);
+        assert_eq!(decode_ext_value("UTF-8''%FF%FE.pdf"), None);
+        assert_eq!(decode_ext_value("UTF-8''"), None);
+    }
+
+    #[test]
+    fn malformed_extended_filename_falls_back_to_plain() {
+        let headers = disposition("attachment; filename*=UTF-8''%FF.pdf; filename=\"plain.pdf\"");
+        assert_eq!(
+            extract_filename("https://example.com/download", &headers),
+            "plain.pdf"
+        );
+    }
+
+    #[test]
+    fn unknown_charset_falls_back_to_plain() {
+        let headers = disposition("attachment; filename*=KOI8-R''%E6.pdf; filename=plain.pdf");
+        assert_eq!(
+            extract_filename("https://example.com/download", &headers),
+            "plain.pdf"
+        );
+    }
+
+    #[test]
+    fn unusable_extended_filename_alone_falls_back_to_url() {
+        let headers = disposition("attachment; filename*=KOI8-R''%E6.pdf");
+        assert_eq!(
+            extract_filename("https://example.com/files/report.pdf", &headers),
+            "report.pdf"
+        );
+    }
+
+    #[test]
+    fn strips_quotes_from_filename() {
+        let headers = disposition("attachment; filename=\"report (final).pdf\"");
+        assert_eq!(
+            extract_filename("https://example.com/download", &headers),
+            "report (final).pdf"
+        );
+    }
+
+    #[test]
+    fn keeps_unquoted_filename() {
+        let headers = disposition("attachment; filename=plain.zip");
+        assert_eq!(
+            extract_filename("https://example.com/download", &headers),
+            "plain.zip"
+        );
+    }
+
+    #[test]
+    fn unquote_resolves_backslash_escapes() {
+        assert_eq!(unquote(r#""say \"hi\".txt""#), r#"say "hi".txt"#);
+        assert_eq!(unquote(r#""back\\slash.txt""#), r"back\slash.txt");
+        assert_eq!(unquote("bare.txt"), "bare.txt");
+        assert_eq!(unquote("\"unterminated.txt"), "\"unterminated.txt");
+    }
+
+    #[test]
+    fn semicolon_inside_quotes_does_not_split_parameters() {
+        let header = "attachment; filename=\"a;b.txt\"; size=3";
+        assert_eq!(disposition_param(header, "filename"), Some("\"a;b.txt\""));
+        assert_eq!(disposition_param(header, "size"), Some("3"));
+        let header = r#"attachment; filename="x\";y.txt"; size=3"#;
+        assert_eq!(disposition_param(header, "size"), Some("3"));
+        assert_eq!(
+            server_filename("https://example.com/download", &disposition(header), false),
+            "x\";y.txt"
+        );
+    }
+
+    #[test]
+    fn header_filename_keeps_only_the_last_component() {
+        assert_eq!(
+            sanitize_header_filename("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            sanitize_header_filename(r"C:\temp\evil.exe").as_deref(),
+            Some("evil.exe")
+        );
+        assert_eq!(sanitize_header_filename("dir/.."), None);
+        assert_eq!(sanitize_header_filename("  "), None);
+    }
+
+    #[test]
+    fn ignores_query_string() {
+        assert_eq!(
+            extract_filename(
+                "https://host/path/archive.tar.gz?sig=xyz",
+                &HeaderMap::new()
+            ),
+            "archive.tar.gz"
+        );
+        assert_eq!(
+            extract_filename("https://host/file.zip?next=/a/b.exe", &HeaderMap::new()),
+            "file.zip"
+        );
+    }
+
+    #[test]
+    fn ignores_fragment() {
+        assert_eq!(
+            extract_filename("https://host/file.zip#frag", &HeaderMap::new()),
+            "file.zip"
+        );
+        assert_eq!(
+            extract_filename(
+                "https://host/file.zip?token=abc#part.exe",
+                &HeaderMap::new()
+            ),
+            "file.zip"
+        );
+    }
+
+    #[test]
+    fn keeps_double_extensions() {
+        for name in [
+            "archive.tar.gz",
+            "archive.tar.bz2",
+            "archive.tar.xz",
+            "archive.tar.zst",
+        ] {
+            let url = format!("https://host/{}", name);
+            assert_eq!(extract_filename(&url, &HeaderMap::new()), name);
+        }
+        assert_eq!(
+            extract_filename("https://host/ARCHIVE.TAR.GZ", &HeaderMap::new()),
+            "ARCHIVE.TAR.GZ"
+        );
+        // 不是 tar 时只保留最后一个扩展名
+        assert_eq!(
+            extract_filename("https://host/my.file.gz", &HeaderMap::new()),
+            "my_file.gz"
+        );
+    }
+
+    #[test]
+    fn numbers_before_the_extension() {
+        assert_eq!(numbered_filename("archive.tar.gz", 1), "archive (1).tar.gz");
+        assert_eq!(numbered_filename("archive.tar.xz", 2), "archive (2).tar.xz");
+        assert_eq!(numbered_filename("report.pdf", 2), "report (2).pdf");
+        assert_eq!(numbered_filename("README", 1), "README (1)");
+        assert_eq!(numbered_filename(".bashrc", 1), ".bashrc (1)");
+    }
+
+    #[test]
+    fn windows_filename_prefixes_reserved_names() {
+        assert_eq!(windows_filename("nul.txt"), "_nul.txt");
+        assert_eq!(windows_filename("Com1.tar.gz"), "_Com1.tar.gz");
+        assert_eq!(windows_filename("CON"), "_CON");
+        assert_eq!(windows_filename("lpt9.log"), "_lpt9.log");
+        assert_eq!(windows_filename("nul .txt"), "_nul .txt");
+        assert_eq!(windows_filename("console.txt"), "console.txt");
+        assert_eq!(windows_filename("COM10.txt"), "COM10.txt");
+    }
+
+    #[test]
+    fn windows_filename_trims_trailing_dots_and_spaces() {
+        assert_eq!(windows_filename("report. . "), "report");
+        assert_eq!(windows_filename("notes.txt."), "notes.txt");
+        assert_eq!(windows_filename("nul. "), "_nul");
+        assert_eq!(windows_filename(" . "), "download.bin");
+        assert_eq!(windows_filename("..."), "download.bin");
+    }
+
+    #[test]
+    fn windows_filename_replaces_reserved_characters() {
+        assert_eq!(
+            windows_filename(r#"a<b>c:d"e/f\g|h?i*.txt"#),
+            "a_b_c_d_e_f_g_h_i_.txt"
+        );
+        assert_eq!(windows_filename("tab\there.txt"), "tab_here.txt");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn url_basename_nul_txt_can_be_created_on_windows() {
+        assert_eq!(
+            extract_filename("https://host/nul.txt", &HeaderMap::new()),
+            "_nul.txt"
+        );
+    }
+
+    #[test]
+    fn url_directories_mirror_the_url() {
+        assert_eq!(
+            url_directories("https://example.com/a/b%20c/file.zip", 0),
+            Path::new("example.com/a/b c")
+        );
+        assert_eq!(
+            url_directories("https://example.com:8443/a/b/file.zip", 1),
+            Path::new("example.com_8443/b")
+        );
+        assert_eq!(
+            url_directories("https://example.com/a/../b/./file.zip", 0),
+            Path::new("example.com/b")
+        );
+    }
+
+    #[test]
+    fn url_directories_sanitize_ipv6_hosts() {
+        let dirs = url_directories("http://[::1]:8080/pub/file.zip", 0);
+        if cfg!(windows) {
+            assert_eq!(dirs, Path::new("[__1]_8080/pub"));
+        } else {
+            assert_eq!(dirs, Path::new("[::1]_8080/pub"));
+        }
+        assert_eq!(windows_filename("[::1]_8080"), "[__1]_8080");
+    }
+}
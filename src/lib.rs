@@ -0,0 +1,2366 @@
+use fs4::tokio::AsyncFileExt;
+use futures::StreamExt;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::header::HeaderMap;
+use reqwest::header::{IF_RANGE, RANGE, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+mod adaptive;
+mod benchmark;
+mod checksum;
+mod client;
+mod confirm;
+mod cookies;
+mod decode;
+mod error;
+mod filename;
+mod local;
+mod manifest;
+mod metalink;
+mod netrc;
+mod probe;
+mod progress;
+mod quota;
+mod resolve;
+mod resume;
+mod sidecar;
+mod size;
+#[cfg(test)]
+mod testing;
+mod throttle;
+
+pub use adaptive::{ConnectionPermit, Connections};
+pub use benchmark::{benchmark, best_level, BenchmarkResult, BENCHMARK_LEVELS};
+pub use checksum::Algorithm;
+use checksum::Digests;
+pub use client::{build_client, build_client_sharing_jar, build_client_with_jar};
+pub use cookies::CookieJar;
+pub use error::{DownloadError, FailedChunk};
+use filename::numbered_filename;
+pub use filename::{extract_filename, extract_unicode_filename, url_directories};
+pub use manifest::{parse_manifest, Manifest};
+pub use metalink::{parse_metalink, MetalinkFile};
+pub use netrc::Netrc;
+pub use probe::RemoteFile;
+pub use progress::{Progress, ProgressEvent};
+pub use quota::Quota;
+pub use resolve::IpVersion;
+use resume::{ChunkLayout, ResumeState};
+use sidecar::SidecarDigest;
+pub use size::parse_size;
+pub use throttle::RateLimiter;
+
+/// User agent used unless `DownloadOptions::user_agent` says otherwise.
+pub const DEFAULT_USER_AGENT: &str = concat!("iDownloader/", env!("CARGO_PKG_VERSION"));
+
+/// Smallest chunk size accepted for `DownloadOptions::chunk_size`.
+pub const MIN_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Smallest chunk a file is split into when the chunk count follows from
+/// `DownloadOptions::max_chunks`, so small files are not fetched in
+/// thousands of tiny requests.
+pub const MIN_AUTO_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Settings for a single download, mirroring the command line flags.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Directory to save into, defaults to the user's download directory
+    pub output: Option<PathBuf>,
+    /// Maximum number of chunks to split the file into. Fewer are used when
+    /// chunks would be smaller than `MIN_AUTO_CHUNK_SIZE`.
+    pub max_chunks: u64,
+    /// Bytes per chunk; when set the chunk count follows from the file size
+    /// and `max_chunks` is ignored. Values below `MIN_CHUNK_SIZE` are raised
+    /// to it.
+    pub chunk_size: Option<u64>,
+    /// Maximum number of attempts per chunk
+    pub max_retries: u64,
+    /// Maximum number of chunks downloaded at the same time
+    pub max_connections: usize,
+    /// Start with fewer connections and adjust the number during the
+    /// download, up to `max_connections`: more while throughput improves,
+    /// fewer after resets or 429/503 answers
+    pub adaptive: bool,
+    /// Other URLs serving the same file. Chunks are spread across them and,
+    /// when one fails, retried on the next. Only used for range downloads.
+    pub mirrors: Vec<String>,
+    /// Expected SHA-256 digest of the file, as hex
+    pub sha256: Option<String>,
+    /// Expected BLAKE3 digest of the file, as hex
+    pub blake3: Option<String>,
+    /// Digest to compute of the saved file, returned in `Download::hash`
+    pub hash: Option<Algorithm>,
+    /// Expected size of the file; the download fails before fetching
+    /// anything if the server reports a different one
+    pub size: Option<u64>,
+    /// Largest file to download; a bigger one fails before anything is
+    /// fetched, or as soon as that many bytes have arrived if the server did
+    /// not say how big it is
+    pub max_size: Option<u64>,
+    /// Ask on the terminal before downloading a file bigger than this; the
+    /// download fails with `DownloadError::Declined` unless the answer is yes
+    pub confirm_size: Option<u64>,
+    /// Verify the file against the `.sha256`, `.sha1` or `.md5` checksum file
+    /// next to it, locally or on the server; the download fails if there is
+    /// none
+    pub verify_sidecar: bool,
+    /// Fail when the server answers with an HTML page for a URL that does not
+    /// look like one, as CDNs do for "access denied" pages sent with a 200
+    pub fail_on_html: bool,
+    /// Fail unless the server reports this Content-Type, e.g.
+    /// `application/zip`; `image/*` accepts any subtype
+    pub content_type: Option<String>,
+    /// Extra headers sent with every request
+    pub headers: HeaderMap,
+    /// User name for HTTP Basic authentication
+    pub user: Option<String>,
+    /// Password for HTTP Basic authentication
+    pub password: Option<String>,
+    /// Token sent as `Authorization: Bearer <TOKEN>`
+    pub bearer: Option<String>,
+    /// Proxy URL (http, https or socks5); the `HTTP_PROXY`/`HTTPS_PROXY`
+    /// environment variables are used when this is `None`
+    pub proxy: Option<String>,
+    /// Cap on the combined download speed, in bytes per second
+    pub limit_rate: Option<u64>,
+    /// Timeout for the HEAD request and each chunk request as a whole. The
+    /// single-stream fallback is not limited, as it carries the whole file.
+    pub timeout: Option<Duration>,
+    /// Timeout for establishing a connection
+    pub connect_timeout: Option<Duration>,
+    /// Only connect over this IP version; `None` uses whatever the system
+    /// resolver returns
+    pub ip_version: Option<IpVersion>,
+    /// Host names pinned to addresses instead of looking them up, like
+    /// curl's `--resolve`. Requests keep the original Host header and TLS
+    /// server name, and connect to the URL's port.
+    pub resolve: Vec<(String, Vec<IpAddr>)>,
+    /// Maximum number of redirects followed per request; 0 does not follow
+    /// redirects at all
+    pub max_redirects: usize,
+    /// Accept invalid or self-signed TLS certificates. Only meant for testing
+    /// against servers you control.
+    pub insecure: bool,
+    /// PEM files with extra root certificates to trust, each holding one or
+    /// more certificates
+    pub ca_certs: Vec<PathBuf>,
+    /// PEM file with the client certificate chain for mutual TLS, leaf first
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key for `client_cert`; when `None` the key
+    /// is read from `client_cert` as well
+    pub client_key: Option<PathBuf>,
+    /// Overwrite an existing file (or unresumable `.part` file) instead of
+    /// failing
+    pub force: bool,
+    /// Save under `name (1).ext`, `name (2).ext`, ... instead of failing when
+    /// the file already exists; ignored when `force` is set
+    pub auto_rename: bool,
+    /// Keep non-ASCII characters in file names taken from the URL instead of
+    /// replacing them with `_`
+    pub preserve_unicode: bool,
+    /// Save under the URL's host and directories inside `output`, e.g.
+    /// `example.com/pub/file.zip`, so files with the same name do not clash
+    pub force_directories: bool,
+    /// Number of leading URL directories left out with `force_directories`
+    pub cut_dirs: usize,
+    /// Give the saved file the server's Last-Modified time, when it sent one
+    pub timestamp: bool,
+    /// Skip the download when the local file has the remote size and is not
+    /// older than its Last-Modified time; otherwise replace it
+    pub timestamping: bool,
+    /// Directory for the `.part` and `.idm` files while downloading, and for
+    /// the temporary file when writing to stdout. Defaults to the output
+    /// directory (or the system temp directory for stdout).
+    pub temp_dir: Option<PathBuf>,
+    /// Leave partial files in place when a download fails, even ones that
+    /// cannot be resumed, so they can be inspected
+    pub keep_temp: bool,
+    /// Check that there is room for the whole file before downloading it
+    pub space_check: bool,
+    /// Receives progress updates of every download using these options, for
+    /// callers that draw their own progress UI
+    pub events: Option<mpsc::Sender<ProgressEvent>>,
+    /// Stops every download using these options when cancelled; they then
+    /// fail with `DownloadError::Cancelled`
+    pub cancel: Option<CancellationToken>,
+    /// HTTP client to use instead of building one from these options. The
+    /// client-level settings (headers, credentials, proxy, cookies, user
+    /// agent, connect timeout, redirects, IP version and TLS) are then
+    /// taken from it and the corresponding fields here are ignored.
+    pub client: Option<reqwest::Client>,
+    /// Clients for requests to particular hosts, keyed by host name, such as
+    /// ones carrying `.netrc` credentials that must not reach other hosts.
+    /// Every request picks the client by its own URL, so mirrors and
+    /// redirect targets on other hosts use `client` instead.
+    pub host_clients: HashMap<String, reqwest::Client>,
+    /// Extra rounds in which every chunk that used up its retries is tried
+    /// again, before the download is reported as incomplete
+    pub retry_passes: u64,
+    /// Shows the progress bar as part of a group, e.g. when several files
+    /// download at once. Each bar is then labelled with its file name.
+    pub progress: Option<MultiProgress>,
+    /// indicatif template for the progress bars instead of the built-in one.
+    /// An invalid template falls back to the built-in one
+    pub progress_template: Option<String>,
+    /// Characters the bar is drawn with, filled to empty, instead of `#>-`;
+    /// at least two, all of the same width
+    pub progress_chars: Option<String>,
+    /// Aggregate bar advanced by every download using these options; each
+    /// adds its size to the bar's length when it starts
+    pub total_progress: Option<ProgressBar>,
+    /// Write the file to stdout instead of saving it; `output` is ignored
+    pub stdout: bool,
+    /// Download the file but throw the data away instead of writing it
+    /// anywhere, to measure the network without disk I/O getting in the way.
+    /// `output` is ignored and checksums are not verified
+    pub discard: bool,
+    /// Write the response headers of the probe request to `<file>.headers`
+    pub save_headers: bool,
+    /// Print the status and response headers of the probe request to stderr
+    pub dump_headers: bool,
+    /// Write a `Manifest` of the finished download to `<file>.manifest.json`
+    pub manifest: bool,
+    /// User-Agent header sent with every request
+    pub user_agent: String,
+    /// Extra `name=value` cookies sent with every request
+    pub cookies: Vec<String>,
+    /// Netscape-format cookie file to load into the cookie store
+    pub load_cookies: Option<PathBuf>,
+    /// Base delay between retries of a chunk, doubled on each attempt
+    pub retry_wait: Duration,
+    /// Cap on the bytes downloaded by every download sharing these options;
+    /// once it is used up no new requests are made
+    pub quota: Option<Arc<Quota>>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            output: None,
+            max_chunks: 500,
+            chunk_size: None,
+            max_retries: 3,
+            max_connections: 16,
+            adaptive: false,
+            mirrors: Vec::new(),
+            sha256: None,
+            blake3: None,
+            hash: None,
+            size: None,
+            max_size: None,
+            confirm_size: None,
+            verify_sidecar: false,
+            fail_on_html: false,
+            content_type: None,
+            headers: HeaderMap::new(),
+            user: None,
+            password: None,
+            bearer: None,
+            proxy: None,
+            limit_rate: None,
+            timeout: Some(Duration::from_secs(300)),
+            connect_timeout: Some(Duration::from_secs(30)),
+            ip_version: None,
+            resolve: Vec::new(),
+            max_redirects: 10,
+            insecure: false,
+            ca_certs: Vec::new(),
+            client_cert: None,
+            client_key: None,
+            force: false,
+            auto_rename: false,
+            preserve_unicode: false,
+            force_directories: false,
+            cut_dirs: 0,
+            timestamp: true,
+            timestamping: false,
+            temp_dir: None,
+            keep_temp: false,
+            space_check: true,
+            events: None,
+            cancel: None,
+            client: None,
+            host_clients: HashMap::new(),
+            retry_passes: 1,
+            progress: None,
+            progress_template: None,
+            progress_chars: None,
+            total_progress: None,
+            stdout: false,
+            discard: false,
+            save_headers: false,
+            dump_headers: false,
+            manifest: false,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            cookies: Vec::new(),
+            load_cookies: None,
+            retry_wait: Duration::from_secs(1),
+            quota: None,
+        }
+    }
+}
+
+/// The result of a successful `download`.
+#[derive(Debug, Clone)]
+pub struct Download {
+    /// Path of the saved file, `-` when writing to stdout, or empty when the
+    /// data was discarded
+    pub path: PathBuf,
+    /// The local file was already up to date, so nothing was downloaded
+    pub skipped: bool,
+    /// Size of the file
+    pub bytes: u64,
+    /// Number of chunks the file was split into; 1 for a single stream and
+    /// 0 when nothing had to be fetched
+    pub chunks: u64,
+    /// Lowercase hex digest of the file, when `DownloadOptions::hash` is set
+    pub hash: Option<String>,
+}
+
+/// Downloads `url` and returns where it was saved.
+pub async fn download(url: &str, opts: &DownloadOptions) -> Result<Download, DownloadError> {
+    let Some(cancel) = &opts.cancel else {
+        return download_file(url, opts).await;
+    };
+    // 取消时直接丢弃下载任务：分片任务随 JoinSet 中止，无法续传的临时文件在析构时删除，
+    // 可以续传的 .part 和 .idm 保留
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(DownloadError::Cancelled),
+        result = download_file(url, opts) => result,
+    }
+}
+
+/// Asks the server about `url` without downloading it, like `wget --spider`.
+pub async fn inspect(url: &str, opts: &DownloadOptions) -> Result<RemoteFile, DownloadError> {
+    let client = match &opts.client {
+        Some(client) => client.clone(),
+        None => build_client(opts)?,
+    };
+    let url = url.trim();
+    let probe = probe::probe(client_for(opts, &client, url), url, opts.timeout);
+    match &opts.cancel {
+        Some(cancel) => tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(DownloadError::Cancelled),
+            result = probe => result,
+        },
+        None => probe.await,
+    }
+}
+
+/// What `download` would do with a URL, as worked out by `plan`.
+#[derive(Debug, Clone)]
+pub struct Plan {
+    /// URL after following redirects
+    pub url: String,
+    /// Where the file would be saved, or `-` when writing to stdout
+    pub path: PathBuf,
+    /// Size of the file, if the server sent it
+    pub size: Option<u64>,
+    pub accept_ranges: bool,
+    /// Number of requests the file is split into; 1 for a single stream and
+    /// 0 for an empty file
+    pub chunks: u64,
+    /// Size of every chunk but the last, when the file is downloaded in
+    /// ranges
+    pub chunk_size: Option<u64>,
+}
+
+/// Asks the server about `url` and works out the file name and chunk layout
+/// `download` would use, without downloading or creating anything.
+pub async fn plan(url: &str, opts: &DownloadOptions) -> Result<Plan, DownloadError> {
+    let mut remote = inspect(url, opts).await?;
+    apply_expected_size(opts, &mut remote)?;
+    check_max_size(opts, &remote)?;
+    check_content_type(opts, url, &remote)?;
+    let path = if opts.stdout {
+        PathBuf::from("-")
+    } else {
+        let output_dir = target_dir(opts, url)?;
+        output_dir.join(local_filename(opts, &remote, &output_dir))
+    };
+    let (chunks, chunk_size) = match remote.content_length {
+        Some(0) => (0, None),
+        Some(length) if remote.accept_ranges => {
+            let layout = chunk_layout(opts, length);
+            (layout.chunk_count, Some(layout.chunk_size))
+        }
+        _ => (1, None),
+    };
+    Ok(Plan {
+        url: remote.url,
+        path,
+        size: remote.content_length,
+        accept_ranges: remote.accept_ranges,
+        chunks,
+        chunk_size,
+    })
+}
+
+async fn download_file(url: &str, opts: &DownloadOptions) -> Result<Download, DownloadError> {
+    let url = url.trim();
+    // 批量下载时前面的文件已经用完配额，后面的就不再开始
+    check_quota(opts)?;
+
+    let client = match &opts.client {
+        Some(client) => client.clone(),
+        None => build_client(opts)?,
+    };
+    let mut remote = probe::probe(client_for(opts, &client, url), url, opts.timeout).await?;
+    if opts.dump_headers {
+        dump_headers(&remote);
+    }
+    apply_expected_size(opts, &mut remote)?;
+    check_max_size(opts, &remote)?;
+    check_content_type(opts, url, &remote)?;
+
+    // 输出到 stdout 时先下载到临时文件再按顺序写出，不需要文件名，也不支持续传
+    if opts.stdout {
+        confirm::confirm_size(opts, &remote).await?;
+        let temp = match &opts.temp_dir {
+            Some(dir) => NamedTempFile::new_in(dir),
+            None => NamedTempFile::new(),
+        }
+        .map_err(DownloadError::io("failed to create temp file"))?;
+        if let Some(length) = remote.content_length.filter(|_| opts.space_check) {
+            let dir = temp.path().parent().unwrap_or(Path::new("."));
+            check_space(dir, length)?;
+        }
+        let result = download_to_stdout(&client, url, opts, &remote, temp.path()).await;
+        if result.is_err() && opts.keep_temp {
+            if let Ok(path) = temp.into_temp_path().keep() {
+                report_kept(opts, &path);
+            }
+        }
+        return result;
+    }
+
+    // 测速时照常分片下载，只是数据不落盘，也就没有文件名、续传和校验
+    if opts.discard {
+        confirm::confirm_size(opts, &remote).await?;
+        let (layout, bytes) = fetch(&client, url, opts, &remote, Path::new(""), None, None).await?;
+        return Ok(Download {
+            path: PathBuf::new(),
+            skipped: false,
+            bytes,
+            chunks: layout.chunk_count,
+            hash: None,
+        });
+    }
+
+    let output_dir = target_dir(opts, url)?;
+    let filename = local_filename(opts, &remote, &output_dir);
+    tokio::fs::create_dir_all(&output_dir)
+        .await
+        .map_err(DownloadError::io("failed to create output directory"))?;
+    // 下载过程中的 .part 和 .idm 可以放在单独的目录里，完成后再移到输出目录
+    let staging_dir = match &opts.temp_dir {
+        Some(dir) => {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(DownloadError::io("failed to create temp directory"))?;
+            tempfile::tempfile_in(dir)
+                .map_err(DownloadError::io("temp directory is not writable"))?;
+            dir.clone()
+        }
+        None => output_dir.clone(),
+    };
+    let file_path = output_dir.join(&filename);
+    let part_path = staging_dir.join(format!("{}.part", filename));
+    let state_path = staging_dir.join(format!("{}.idm", filename));
+    if opts.timestamping && is_up_to_date(&file_path, &remote) {
+        return Ok(Download {
+            hash: compute_hash(opts, &file_path, &[]).await?,
+            path: file_path,
+            skipped: true,
+            bytes: remote.content_length.unwrap_or(0),
+            chunks: 0,
+        });
+    }
+    if file_path.exists() && !opts.force && !opts.timestamping {
+        return Err(DownloadError::FileExists(file_path));
+    }
+    // 已是最新或已存在而不会下载的文件不必询问
+    confirm::confirm_size(opts, &remote).await?;
+    // 下载开始前就写好，下载失败时也能据此排查
+    if opts.save_headers {
+        let mut headers_path = file_path.clone().into_os_string();
+        headers_path.push(".headers");
+        std::fs::write(&headers_path, remote.header_lines())
+            .map_err(DownloadError::io("failed to write headers file"))?;
+    }
+    if let Some(length) = remote.content_length.filter(|_| opts.space_check) {
+        // 已有的 .part 在开始时就按完整大小分配过空间
+        let existing = std::fs::metadata(&part_path).map_or(0, |m| m.len());
+        check_space(&staging_dir, length.saturating_sub(existing))?;
+        // 临时目录在另一个文件系统上时，完成后还要整个复制过去
+        if !same_filesystem(&staging_dir, &output_dir) {
+            check_space(&output_dir, length)?;
+        }
+    }
+
+    // 先找到校验文件再开始下载，找不到时不必白白下载整个文件；
+    // 校验文件和重定向后的文件放在一起，而不是用户给出的地址旁边
+    let sidecar = if opts.verify_sidecar {
+        Some(
+            sidecar::find_sidecar(
+                client_for(opts, &client, &remote.url),
+                &remote.url,
+                Some(&file_path),
+                opts.timeout,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    let digests = inline_digests(opts, sidecar.as_ref());
+    let (layout, bytes, hash, sha256) = async {
+        let (layout, _) = fetch(
+            &client,
+            url,
+            opts,
+            &remote,
+            &part_path,
+            Some(&state_path),
+            digests.clone(),
+        )
+        .await?;
+        let bytes = verify_size(opts, &part_path, remote.content_length).await?;
+        let computed = finish_digests(digests, bytes);
+        verify_checksum(opts, &part_path, sidecar.as_ref(), &computed).await?;
+        let hash = compute_hash(opts, &part_path, &computed).await?;
+        // 清单里总是记录 SHA-256，重新下载时据此校验
+        let sha256 = if opts.manifest {
+            Some(
+                digest_of(&part_path, Algorithm::Sha256, &computed)
+                    .await
+                    .map_err(DownloadError::io("failed to read downloaded file"))?,
+            )
+        } else {
+            None
+        };
+        Ok::<_, DownloadError>((layout, bytes, hash, sha256))
+    }
+    .await
+    .inspect_err(|_| report_kept(opts, &part_path))?;
+
+    let mut part = OpenOptions::new()
+        .write(true)
+        .open(&part_path)
+        .await
+        .map_err(DownloadError::io("failed to open part file"))?;
+    // 沿用服务器上的修改时间，镜像时可以据此判断文件是否有更新
+    if let Some(modified) = remote.last_modified.filter(|_| opts.timestamp) {
+        let std_file = part.into_std().await;
+        std_file
+            .set_modified(modified)
+            .map_err(DownloadError::io("failed to set modification time"))?;
+        part = File::from_std(std_file);
+    }
+    // 先把数据刷到磁盘再改名，避免断电后留下一个名字正确但内容不完整的文件
+    part.sync_all()
+        .await
+        .map_err(DownloadError::io("failed to sync part file"))?;
+    move_into_place(&part_path, &file_path)?;
+    if let Some(sha256) = sha256 {
+        let manifest = Manifest {
+            url: url.to_string(),
+            final_url: remote.url.clone(),
+            size: bytes,
+            chunks: layout.chunk_count,
+            chunk_size: layout.chunk_size,
+            even: layout.even,
+            sha256,
+            headers: remote.header_lines().lines().map(str::to_string).collect(),
+        };
+        let mut manifest_path = file_path.clone().into_os_string();
+        manifest_path.push(".manifest.json");
+        std::fs::write(
+            &manifest_path,
+            serde_json::to_string_pretty(&manifest).unwrap() + "\n",
+        )
+        .map_err(DownloadError::io("failed to write manifest file"))?;
+    }
+    Ok(Download {
+        path: file_path,
+        skipped: false,
+        bytes,
+        chunks: layout.chunk_count,
+        hash,
+    })
+}
+
+/// Prints the probe response of `remote` to stderr the way `curl -v` shows
+/// it, one `< ` line per header after the status.
+fn dump_headers(remote: &RemoteFile) {
+    // 同时下载多个文件时整块写出，避免不同文件的行交错在一起
+    let mut dump = format!("< {}\n< {}\n", remote.url, remote.status);
+    for line in remote.header_lines().lines() {
+        dump.push_str("< ");
+        dump.push_str(line);
+        dump.push('\n');
+    }
+    eprint!("{}", dump);
+}
+
+/// Checks the size the server reported against `opts.size`, and fills it in
+/// when the server did not send one so the result can still be verified.
+fn apply_expected_size(
+    opts: &DownloadOptions,
+    remote: &mut RemoteFile,
+) -> Result<(), DownloadError> {
+    match (opts.size, remote.content_length) {
+        (Some(expected), Some(actual)) if expected != actual => {
+            Err(DownloadError::SizeMismatch { expected, actual })
+        }
+        // 压缩传输时按解压后的数据流下载，不预设大小
+        (Some(expected), None) if remote.content_encoding.is_none() => {
+            remote.content_length = Some(expected);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_max_size(opts: &DownloadOptions, remote: &RemoteFile) -> Result<(), DownloadError> {
+    match (opts.max_size, remote.content_length) {
+        (Some(limit), Some(length)) if length > limit => Err(DownloadError::TooLarge(limit)),
+        _ => Ok(()),
+    }
+}
+
+/// Rejects `remote` if its Content-Type is not what `opts` expects, before
+/// anything is written.
+fn check_content_type(
+    opts: &DownloadOptions,
+    url: &str,
+    remote: &RemoteFile,
+) -> Result<(), DownloadError> {
+    // 只比较 MIME 类型本身，忽略 charset 等参数
+    let actual = remote
+        .content_type
+        .as_deref()
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    if let Some(expected) = &opts.content_type {
+        let expected = expected.trim().to_ascii_lowercase();
+        let matches = actual
+            .as_deref()
+            .is_some_and(|actual| match expected.strip_suffix("/*") {
+                Some(prefix) => actual.split('/').next() == Some(prefix),
+                None => actual == expected,
+            });
+        if !matches {
+            return Err(DownloadError::UnexpectedContentType {
+                expected,
+                actual: actual.unwrap_or_else(|| "none".to_string()),
+            });
+        }
+    }
+    let is_html = matches!(
+        actual.as_deref(),
+        Some("text/html" | "application/xhtml+xml")
+    );
+    if opts.fail_on_html && is_html && !looks_like_page(url) {
+        return Err(DownloadError::HtmlResponse);
+    }
+    Ok(())
+}
+
+/// Whether `url` names a web page rather than a file, judging by its
+/// extension.
+fn looks_like_page(url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    let ext = Path::new(url.path())
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    matches!(
+        ext.as_deref(),
+        Some("html" | "htm" | "xhtml" | "shtml" | "php" | "asp" | "aspx" | "jsp")
+    )
+}
+
+/// The directory downloads are saved in.
+fn output_dir(opts: &DownloadOptions) -> Result<PathBuf, DownloadError> {
+    match &opts.output {
+        Some(dir) => Ok(dir.clone()),
+        None => dirs::download_dir().ok_or(DownloadError::NoDownloadDir),
+    }
+}
+
+/// The directory `url` is saved in: the output directory, plus the URL's
+/// own directories with `opts.force_directories`.
+fn target_dir(opts: &DownloadOptions, url: &str) -> Result<PathBuf, DownloadError> {
+    let dir = output_dir(opts)?;
+    if opts.force_directories {
+        Ok(dir.join(url_directories(url, opts.cut_dirs)))
+    } else {
+        Ok(dir)
+    }
+}
+
+/// The name `remote` is saved under in `output_dir`.
+fn local_filename(opts: &DownloadOptions, remote: &RemoteFile, output_dir: &Path) -> String {
+    // 重定向后的地址通常带有真正的文件名
+    let mut filename = remote_filename(opts, remote);
+    if opts.auto_rename && !opts.force {
+        // 和浏览器一样在扩展名前加上序号，直到找到没有被占用的文件名
+        let original = filename.clone();
+        let mut n = 1;
+        while output_dir.join(&filename).exists() {
+            filename = numbered_filename(&original, n);
+            n += 1;
+        }
+    }
+    filename
+}
+
+/// How a file of `content_length` bytes, at least one, is split. A chunk
+/// size asked for is kept for every chunk but a shorter last one; otherwise
+/// the file is split into equal chunks, differing by at most one byte.
+fn chunk_layout(opts: &DownloadOptions, content_length: u64) -> ChunkLayout {
+    match opts.chunk_size {
+        Some(size) => {
+            let size = size.max(MIN_CHUNK_SIZE);
+            ChunkLayout {
+                content_length,
+                chunk_size: size,
+                chunk_count: content_length.div_ceil(size),
+                even: false,
+            }
+        }
+        None => {
+            // 分片数不超过字节数，每个分片至少有一个字节
+            let count = opts
+                .max_chunks
+                .min(content_length / MIN_AUTO_CHUNK_SIZE)
+                .min(content_length)
+                .max(1);
+            ChunkLayout {
+                content_length,
+                chunk_size: content_length / count,
+                chunk_count: count,
+                even: true,
+            }
+        }
+    }
+}
+
+/// Fetches `remote` into the temp file at `path` and copies it to stdout once
+/// it has been verified.
+async fn download_to_stdout(
+    client: &reqwest::Client,
+    url: &str,
+    opts: &DownloadOptions,
+    remote: &RemoteFile,
+    path: &Path,
+) -> Result<Download, DownloadError> {
+    let sidecar = if opts.verify_sidecar {
+        Some(
+            sidecar::find_sidecar(
+                client_for(opts, client, &remote.url),
+                &remote.url,
+                None,
+                opts.timeout,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+    let digests = inline_digests(opts, sidecar.as_ref());
+    let (layout, _) = fetch(client, url, opts, remote, path, None, digests.clone()).await?;
+    let bytes = verify_size(opts, path, remote.content_length).await?;
+    let computed = finish_digests(digests, bytes);
+    verify_checksum(opts, path, sidecar.as_ref(), &computed).await?;
+    let hash = compute_hash(opts, path, &computed).await?;
+    let mut file = File::open(path)
+        .await
+        .map_err(DownloadError::io("failed to open temp file"))?;
+    let mut stdout = tokio::io::stdout();
+    tokio::io::copy(&mut file, &mut stdout)
+        .await
+        .map_err(DownloadError::io("failed to write to stdout"))?;
+    stdout
+        .flush()
+        .await
+        .map_err(DownloadError::io("failed to write to stdout"))?;
+    Ok(Download {
+        path: PathBuf::from("-"),
+        skipped: false,
+        bytes,
+        chunks: layout.chunk_count,
+        hash,
+    })
+}
+
+/// Tells the user where a failed download was left when `keep_temp` is set.
+fn report_kept(opts: &DownloadOptions, path: &Path) {
+    if opts.keep_temp && path.exists() {
+        log::warn!("Partial download kept at {}", path.display());
+    }
+}
+
+/// Deletes a partial file whose contents can no longer be used, unless the
+/// user asked to keep it for inspection.
+fn discard(opts: &DownloadOptions, path: &Path) -> Result<(), DownloadError> {
+    if opts.keep_temp {
+        return Ok(());
+    }
+    std::fs::remove_file(path).map_err(DownloadError::io("failed to remove part file"))
+}
+
+/// Moves the finished `.part` file to `to`. Across filesystems a rename is
+/// not possible, so the data is copied next to `to` first and renamed from
+/// there, keeping the final name hidden until the file is complete.
+fn move_into_place(from: &Path, to: &Path) -> Result<(), DownloadError> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        result => return result.map_err(DownloadError::io("failed to rename part file")),
+    }
+    let mut staged = to.as_os_str().to_owned();
+    staged.push(".part");
+    let staged = PathBuf::from(staged);
+    std::fs::copy(from, &staged).map_err(DownloadError::io("failed to copy part file"))?;
+    // fs::copy 不保留修改时间，需要从原文件复制过来
+    let modified = std::fs::metadata(from)
+        .and_then(|m| m.modified())
+        .map_err(DownloadError::io("failed to read part file"))?;
+    let copy = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&staged)
+        .map_err(DownloadError::io("failed to open part file"))?;
+    copy.set_modified(modified)
+        .map_err(DownloadError::io("failed to set modification time"))?;
+    copy.sync_all()
+        .map_err(DownloadError::io("failed to sync part file"))?;
+    std::fs::rename(&staged, to).map_err(DownloadError::io("failed to rename part file"))?;
+    std::fs::remove_file(from).map_err(DownloadError::io("failed to remove part file"))
+}
+
+/// Whether the local copy at `path` has the same size as the remote file and
+/// is at least as new. Without a length or Last-Modified nothing can be said,
+/// so the file is treated as outdated.
+fn is_up_to_date(path: &Path, remote: &RemoteFile) -> bool {
+    let (Some(length), Some(modified)) = (remote.content_length, remote.last_modified) else {
+        return false;
+    };
+    std::fs::metadata(path)
+        .and_then(|m| Ok(m.len() == length && m.modified()? >= modified))
+        .unwrap_or(false)
+}
+
+/// Downloads `remote` into `part_path`, in parallel chunks when the server
+/// supports range requests. Without a `state_path` the download cannot be
+/// resumed and `part_path` is overwritten unconditionally. The data is fed
+/// into `digests` as far as it arrives in order. With `opts.discard` nothing
+/// is written and `part_path` is not used. Returns how the file was split
+/// and its size.
+async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+    opts: &DownloadOptions,
+    remote: &RemoteFile,
+    part_path: &Path,
+    state_path: Option<&Path>,
+    digests: Option<SharedDigests>,
+) -> Result<(ChunkLayout, u64), DownloadError> {
+    // 空文件不需要发起任何下载请求
+    if remote.content_length == Some(0) {
+        discard_stale_part(opts, part_path, state_path)?;
+        if !opts.discard {
+            File::create(part_path)
+                .await
+                .map_err(DownloadError::io("failed to create part file"))?;
+        }
+        if let Some(events) = &opts.events {
+            let _ = events.send(ProgressEvent::Started { total: Some(0) }).await;
+            let _ = events.send(ProgressEvent::Finished).await;
+        }
+        return Ok((ChunkLayout::single(0), 0));
+    }
+
+    // 没有文件大小就无法分片，即使服务器支持 Range 也只能单连接下载
+    if remote.accept_ranges && remote.content_length.is_none() {
+        log::info!("Server did not report the file size, downloading in a single stream");
+        discard_stale_part(opts, part_path, state_path)?;
+        let bytes =
+            download_stream(client, opts, remote, part_path, digests.as_deref(), None).await?;
+        return Ok((ChunkLayout::single(bytes), bytes));
+    }
+
+    if remote.accept_ranges {
+        match download_ranged(
+            client,
+            url,
+            opts,
+            remote,
+            part_path,
+            state_path,
+            digests.clone(),
+        )
+        .await
+        {
+            // 探测时声称支持分片，实际请求却返回了完整文件，改为单连接下载，
+            // 并接着读取已经打开的那个响应，不必再请求一次
+            Ok(Ranged::FullResponse(response)) => {
+                log::info!(
+                    "Server sent the whole file instead of a range, it may have changed; reading it from the response"
+                );
+                let bytes = match download_stream(
+                    client,
+                    opts,
+                    remote,
+                    part_path,
+                    digests.as_deref(),
+                    Some(response),
+                )
+                .await
+                {
+                    // 分片请求带有超时，读完整个文件前就可能超时，这时重新请求一次
+                    Err(e) if e.is_timeout() => {
+                        log::info!("Response timed out, downloading in a single stream");
+                        download_stream(client, opts, remote, part_path, digests.as_deref(), None)
+                            .await?
+                    }
+                    result => result?,
+                };
+                Ok((ChunkLayout::single(bytes), bytes))
+            }
+            Err(DownloadError::RangeIgnored) => {
+                log::info!("Server ignored the range request, downloading in a single stream");
+                let bytes =
+                    download_stream(client, opts, remote, part_path, digests.as_deref(), None)
+                        .await?;
+                Ok((ChunkLayout::single(bytes), bytes))
+            }
+            Ok(Ranged::Complete(layout)) => {
+                if let Some(state_path) = state_path {
+                    std::fs::remove_file(state_path)
+                        .map_err(DownloadError::io("failed to remove resume file"))?;
+                }
+                Ok((layout, layout.content_length))
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        match &remote.content_encoding {
+            Some(encoding) => log::info!(
+                "Server sends {}-encoded data, downloading in a single stream",
+                encoding
+            ),
+            None if local::is_file_url(&remote.url) => log::info!("Copying local file"),
+            None => {
+                log::info!("Server does not support range requests, downloading in a single stream")
+            }
+        }
+        discard_stale_part(opts, part_path, state_path)?;
+        let bytes =
+            download_stream(client, opts, remote, part_path, digests.as_deref(), None).await?;
+        Ok((ChunkLayout::single(bytes), bytes))
+    }
+}
+
+/// The client for requests to `url`: the one in `opts.host_clients` for its
+/// host, or else `client`.
+pub(crate) fn client_for<'a>(
+    opts: &'a DownloadOptions,
+    client: &'a reqwest::Client,
+    url: &str,
+) -> &'a reqwest::Client {
+    host_client(&opts.host_clients, url).unwrap_or(client)
+}
+
+fn host_client<'a>(
+    clients: &'a HashMap<String, reqwest::Client>,
+    url: &str,
+) -> Option<&'a reqwest::Client> {
+    if clients.is_empty() {
+        return None;
+    }
+    clients.get(reqwest::Url::parse(url).ok()?.host_str()?)
+}
+
+/// Makes way for a fresh download into `part_path`. A `.part` file with a
+/// resume file next to it was left by an earlier attempt at this download
+/// that can no longer be resumed, so both are deleted. Without a resume
+/// file it may belong to another download and is only overwritten with
+/// `opts.force`.
+fn discard_stale_part(
+    opts: &DownloadOptions,
+    part_path: &Path,
+    state_path: Option<&Path>,
+) -> Result<(), DownloadError> {
+    let Some(state_path) = state_path else {
+        return Ok(());
+    };
+    if !part_path.exists() || opts.force {
+        return Ok(());
+    }
+    if !state_path.exists() {
+        return Err(DownloadError::FileExists(part_path.to_path_buf()));
+    }
+    // 服务器上的文件变了，已下载的分片属于旧版本，从头开始
+    log::info!("The file changed since the last attempt, starting over");
+    std::fs::remove_file(state_path).map_err(DownloadError::io("failed to remove resume file"))?;
+    std::fs::remove_file(part_path).map_err(DownloadError::io("failed to remove part file"))?;
+    Ok(())
+}
+
+/// Checks that `path` is as long as the server said, deleting it otherwise.
+/// Returns the length of the file.
+async fn verify_size(
+    opts: &DownloadOptions,
+    path: &Path,
+    expected: Option<u64>,
+) -> Result<u64, DownloadError> {
+    let actual = tokio::fs::metadata(path)
+        .await
+        .map_err(DownloadError::io("failed to read part file"))?
+        .len();
+    match expected {
+        Some(expected) if actual != expected => {
+            discard(opts, path)?;
+            Err(DownloadError::SizeMismatch { expected, actual })
+        }
+        _ => Ok(actual),
+    }
+}
+
+/// Digests computed while downloading, shared by the chunk tasks.
+type SharedDigests = Arc<AsyncMutex<Digests>>;
+
+/// Sets up hashing during the download for every digest the file will be
+/// checked against or reported with, if there are any.
+fn inline_digests(
+    opts: &DownloadOptions,
+    sidecar: Option<&SidecarDigest>,
+) -> Option<SharedDigests> {
+    let algorithms: Vec<Algorithm> = [
+        opts.sha256.as_ref().map(|_| Algorithm::Sha256),
+        opts.blake3.as_ref().map(|_| Algorithm::Blake3),
+        sidecar.map(|sidecar| sidecar.algorithm),
+        opts.hash,
+        opts.manifest.then_some(Algorithm::Sha256),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    (!algorithms.is_empty()).then(|| Arc::new(AsyncMutex::new(Digests::new(&algorithms))))
+}
+
+/// The digests computed during the download, or none if they do not cover
+/// all `len` bytes of the file, in which case it is hashed again afterwards.
+fn finish_digests(digests: Option<SharedDigests>, len: u64) -> Vec<(Algorithm, String)> {
+    let Some(digests) = digests.and_then(|d| Arc::try_unwrap(d).ok()) else {
+        return Vec::new();
+    };
+    let digests = digests.into_inner();
+    if digests.hashed() != len {
+        log::debug!(
+            "Only {} of {} bytes hashed during the download",
+            digests.hashed(),
+            len
+        );
+        return Vec::new();
+    }
+    digests.finalize()
+}
+
+/// The `algorithm` digest of `path`, taken from `computed` when it was
+/// already worked out during the download.
+async fn digest_of(
+    path: &Path,
+    algorithm: Algorithm,
+    computed: &[(Algorithm, String)],
+) -> io::Result<String> {
+    match computed.iter().find(|(a, _)| *a == algorithm) {
+        Some((_, hex)) => Ok(hex.clone()),
+        None => checksum::hash_file(path, algorithm).await,
+    }
+}
+
+/// Checks `path` against every expected digest: `--sha256`, `--blake3` and
+/// the one from the checksum file. Deletes it on the first mismatch.
+async fn verify_checksum(
+    opts: &DownloadOptions,
+    path: &Path,
+    sidecar: Option<&SidecarDigest>,
+    computed: &[(Algorithm, String)],
+) -> Result<(), DownloadError> {
+    let expected = [
+        opts.sha256.as_ref().map(|hex| (Algorithm::Sha256, hex)),
+        opts.blake3.as_ref().map(|hex| (Algorithm::Blake3, hex)),
+        sidecar.map(|sidecar| (sidecar.algorithm, &sidecar.hex)),
+    ];
+    for (algorithm, expected) in expected.into_iter().flatten() {
+        let actual = digest_of(path, algorithm, computed)
+            .await
+            .map_err(DownloadError::io("failed to read part file"))?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            discard(opts, path)?;
+            return Err(DownloadError::ChecksumMismatch {
+                expected: expected.to_lowercase(),
+                actual,
+            });
+        }
+    }
+    if let Some(sidecar) = sidecar {
+        log::info!("Checksum matches {}", sidecar.location);
+    }
+    Ok(())
+}
+
+/// Computes the digest asked for in `opts.hash`, if any.
+async fn compute_hash(
+    opts: &DownloadOptions,
+    path: &Path,
+    computed: &[(Algorithm, String)],
+) -> Result<Option<String>, DownloadError> {
+    match opts.hash {
+        Some(algorithm) => digest_of(path, algorithm, computed)
+            .await
+            .map(Some)
+            .map_err(DownloadError::io("failed to read downloaded file")),
+        None => Ok(None),
+    }
+}
+
+/// Creates the progress bar for a download of `total` bytes, labelled with
+/// `label` when it is shown together with other downloads.
+fn progress_bar(total: Option<u64>, opts: &DownloadOptions, label: &str) -> Progress {
+    // 多个文件同时下载时在每个进度条前显示文件名
+    let prefix = if opts.progress.is_some() {
+        "{prefix:24!} "
+    } else {
+        ""
+    };
+    let progress_chars = opts.progress_chars.as_deref().unwrap_or("#>-");
+    let custom = opts
+        .progress_template
+        .as_deref()
+        .and_then(|template| ProgressStyle::default_bar().template(template).ok());
+    let pb = match (total, custom) {
+        // 自定义模板同时用于知道和不知道文件大小的情况
+        (total, Some(style)) => {
+            let pb = total.map_or_else(ProgressBar::new_spinner, ProgressBar::new);
+            pb.set_style(style.progress_chars(progress_chars));
+            pb
+        }
+        (Some(total), None) => {
+            let pb = ProgressBar::new(total);
+            pb.set_style(ProgressStyle::default_bar()
+                 .template(&format!("{{spinner:.green}} {}[{{elapsed_precise}}] [{{bar:40.cyan/blue}}] {{bytes}}/{{total_bytes}} {{bytes_per_sec}} ({{eta}})", prefix))
+                 .unwrap()
+                 .progress_chars(progress_chars));
+            pb
+        }
+        // 不知道文件大小时只能显示已下载的字节数
+        (None, None) => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(
+                ProgressStyle::default_spinner()
+                    .template(&format!(
+                        "{{spinner:.green}} {}[{{elapsed_precise}}] {{bytes}} {{bytes_per_sec}}",
+                        prefix
+                    ))
+                    .unwrap(),
+            );
+            pb
+        }
+    };
+    let pb = pb.with_prefix(label.to_string());
+    let pb = match &opts.progress {
+        Some(multi) => multi.add(pb),
+        None => pb,
+    };
+    let progress = Progress::new(pb, opts.events.clone());
+    match &opts.total_progress {
+        Some(total) => progress.with_total(total.clone()),
+        None => progress,
+    }
+}
+
+/// The name the server suggests for `remote`, sanitized as `opts` asks.
+fn remote_filename(opts: &DownloadOptions, remote: &RemoteFile) -> String {
+    if opts.preserve_unicode {
+        extract_unicode_filename(&remote.url, &remote.headers)
+    } else {
+        extract_filename(&remote.url, &remote.headers)
+    }
+}
+
+/// The file name shown on the progress bar of `remote`: the name it is saved
+/// under, or for a temp file the name taken from the server.
+fn bar_label(opts: &DownloadOptions, remote: &RemoteFile, part_path: &Path) -> String {
+    part_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".part"))
+        .map(str::to_string)
+        .unwrap_or_else(|| remote_filename(opts, remote))
+}
+
+/// Downloads `remote` into `part_path` in parallel chunks, recording progress
+/// in `state_path`. The resume state is keyed by the requested `url` rather
+/// than the redirect target, which may change between runs.
+async fn download_ranged(
+    client: &reqwest::Client,
+    url: &str,
+    opts: &DownloadOptions,
+    remote: &RemoteFile,
+    part_path: &Path,
+    state_path: Option<&Path>,
+    digests: Option<SharedDigests>,
+) -> Result<Ranged, DownloadError> {
+    let content_length = remote.content_length.unwrap_or(0);
+    let etag = remote.etag.as_deref();
+    // 如果存在上次中断留下的记录且服务器文件未变化，则继续下载
+    let saved = state_path
+        .and_then(ResumeState::load)
+        .filter(|state| state.matches(url, content_length, etag) && part_path.exists());
+    // 续传时带上 If-Range，文件在此期间变了的话服务器会返回整个新文件
+    let if_range = saved.as_ref().and_then(ResumeState::if_range);
+    let state = match saved {
+        Some(state) => {
+            log::info!(
+                "Resuming download: {}/{} chunks already completed",
+                state.completed.len(),
+                state.chunk_count
+            );
+            state
+        }
+        None => {
+            discard_stale_part(opts, part_path, state_path)?;
+            let layout = chunk_layout(opts, content_length);
+            // 预先创建完整大小的文件，各分片直接写入自己的偏移位置，无需再合并
+            if !opts.discard {
+                let file = File::create(part_path)
+                    .await
+                    .map_err(DownloadError::io("failed to create part file"))?;
+                // 优先用 fallocate 等方式真正预留磁盘空间，文件系统不支持时退回稀疏文件
+                if file.allocate(content_length).await.is_err() {
+                    file.set_len(content_length)
+                        .await
+                        .map_err(DownloadError::io("failed to allocate part file"))?;
+                }
+            }
+            ResumeState::new(url, layout, etag, remote.last_modified)
+        }
+    };
+    if let Some(state_path) = state_path {
+        state
+            .save(state_path)
+            .map_err(DownloadError::io("failed to write resume file"))?;
+    }
+    let layout = state.layout();
+    let chunk_count = layout.chunk_count;
+    log::info!("Will split into {} chunks", chunk_count);
+    let chunk_range = move |i: u64| layout.range(i);
+
+    let pb = progress_bar(
+        Some(content_length),
+        opts,
+        &bar_label(opts, remote, part_path),
+    );
+    pb.set_position(
+        state
+            .completed
+            .iter()
+            .map(|&i| {
+                let (start, end) = chunk_range(i);
+                end - start + 1
+            })
+            .sum(),
+    );
+
+    let state = Arc::new(Mutex::new(state));
+    // 限制同时进行的分片数量，其余分片排队等待
+    let connections = Arc::new(if opts.adaptive {
+        Connections::adaptive(opts.max_connections)
+    } else {
+        Connections::fixed(opts.max_connections)
+    });
+    // 记录每个分片最近一次的错误，下载失败时报告给用户
+    let last_errors = Arc::new(Mutex::new(HashMap::new()));
+    let timings = Arc::new(Mutex::new(Vec::new()));
+    let urls = chunk_sources(client, opts, remote).await?;
+    if urls.len() > 1 {
+        log::info!("Downloading from {} sources", urls.len());
+    }
+    let ctx = Arc::new(ChunkContext {
+        client: client.clone(),
+        host_clients: opts.host_clients.clone(),
+        urls,
+        part_path: part_path.to_path_buf(),
+        pb: pb.clone(),
+        // 所有分片共享同一个限速器，限制的是总速率
+        limiter: opts.limit_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+        timeout: opts.timeout,
+        quota: opts.quota.clone(),
+        connections: opts.adaptive.then(|| connections.clone()),
+        discard: opts.discard,
+        if_range,
+        full_response: Mutex::new(None),
+    });
+    // 每个分片用完自己的重试次数后，再对所有失败的分片整体重试几轮
+    for pass in 0..=opts.retry_passes {
+        let pending: Vec<u64> = {
+            let state = state.lock().unwrap();
+            (0..chunk_count)
+                .filter(|i| !state.completed.contains(i))
+                .collect()
+        };
+        if pending.is_empty() || check_quota(opts).is_err() {
+            break;
+        }
+        if pass > 0 {
+            log::info!(
+                "Retrying {} failed chunks (pass {}/{})",
+                pending.len(),
+                pass,
+                opts.retry_passes
+            );
+            sleep(backoff(opts.retry_wait, opts.max_retries + pass)).await;
+        }
+        // JoinSet 被丢弃时会中止所有分片任务，下载被取消时不会有任务在后台继续写文件
+        let mut tasks = JoinSet::new();
+
+        for i in pending {
+            let (start, end) = chunk_range(i);
+            let ctx = ctx.clone();
+            let state_path = state_path.map(Path::to_path_buf);
+            let state = state.clone();
+            let max_retries = opts.max_retries; // 获取最大重试次数
+            let retry_wait = opts.retry_wait;
+            let connections = connections.clone();
+            let digests = digests.clone();
+            let last_errors = last_errors.clone();
+            let timings = timings.clone();
+            tasks.spawn(async move {
+                let permit = connections.acquire().await;
+                // 从拿到连接开始计时，重试的时间也算在内
+                let started = Instant::now();
+                let mut retries = 0;
+                while retries < max_retries {
+                    // 用完流量配额后不再发起新的请求，未完成的分片留待下次续传
+                    if ctx.quota.as_ref().is_some_and(|q| q.exceeded()) {
+                        break;
+                    }
+                    // 每轮重试都从第一个还没用过的来源重新开始
+                    let url = ctx.source(i, pass * max_retries + retries);
+                    match download_chunk(&ctx, url, start, end).await {
+                        Ok(bytes) => {
+                            timings.lock().unwrap().push(ChunkTiming {
+                                index: i,
+                                elapsed: started.elapsed(),
+                                bytes,
+                            });
+                            // 每完成一个分片就更新记录文件
+                            {
+                                let mut state = state.lock().unwrap();
+                                state.completed.insert(i);
+                                if let Some(state_path) = &state_path {
+                                    if let Err(e) = state.save(state_path) {
+                                        log::warn!("Failed to update resume file: {}", e);
+                                    }
+                                }
+                            }
+                            // 计算哈希不占用连接名额
+                            drop(permit);
+                            if let Some(digests) = &digests {
+                                hash_completed(digests, &state, &ctx.part_path, layout).await;
+                            }
+                            break;
+                        }
+                        // 403、404 这类错误重试也不会成功，直接结束整个下载；
+                        // 有镜像时则换一个来源再试，但磁盘满了换来源也没用
+                        Err(e) if e.is_disk_full() => return Err(e),
+                        Err(e) if !e.is_retryable() && ctx.urls.len() == 1 => return Err(e),
+                        Err(e) => {
+                            retries += 1;
+                            connections.record_error(&e);
+                            // 有镜像时注明出错的来源，便于找出有问题的镜像
+                            let message = if ctx.urls.len() > 1 {
+                                format!("{} ({})", e, url)
+                            } else {
+                                e.to_string()
+                            };
+                            last_errors.lock().unwrap().insert(i, message);
+                            log::warn!(
+                                "Error downloading chunk {}: {}. Retrying ({}/{})...",
+                                i,
+                                e,
+                                retries,
+                                max_retries
+                            );
+                            if retries == max_retries {
+                                log::warn!(
+                                    "Failed to download chunk {} after {} retries",
+                                    i,
+                                    max_retries
+                                );
+                            } else {
+                                // 优先遵守服务器给出的 Retry-After，否则指数退避
+                                let delay = e
+                                    .retry_after()
+                                    .unwrap_or_else(|| backoff(retry_wait, retries));
+                                sleep(delay).await;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            if let Ok(Err(e)) = result {
+                tasks.abort_all();
+                ctx.pb.abandon();
+                // 服务器拒绝或忽略 Range 时，已下载的数据和续传记录都不再可信；
+                // 其他错误（如链接过期返回 403）保留 .part，之后还可以续传
+                if matches!(
+                    e,
+                    DownloadError::RangeNotSatisfiable { .. } | DownloadError::RangeIgnored
+                ) {
+                    let _ = discard(opts, part_path);
+                    if let Some(state_path) = state_path.filter(|_| !opts.keep_temp) {
+                        let _ = std::fs::remove_file(state_path);
+                    }
+                }
+                // 其他错误（如磁盘已满）不能因为收到过完整响应就被吞掉
+                if matches!(e, DownloadError::RangeIgnored) {
+                    if let Some(response) = ctx.full_response.lock().unwrap().take() {
+                        return Ok(Ranged::FullResponse(response));
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    ctx.pb.finish().await;
+    report_timings(&mut timings.lock().unwrap());
+
+    // 最后完成的分片可能没能接上哈希进度，在这里补齐
+    if let Some(digests) = &digests {
+        hash_completed(digests, &state, part_path, layout).await;
+    }
+
+    // 有分片失败时不生成最终文件，保留 .part 以便下次续传
+    let state = state.lock().unwrap();
+    if !state.is_complete() {
+        check_quota(opts)?;
+        let mut last_errors = last_errors.lock().unwrap();
+        let failed = (0..chunk_count)
+            .filter(|i| !state.completed.contains(i))
+            .map(|index| {
+                let (start, end) = chunk_range(index);
+                let error = last_errors.remove(&index);
+                FailedChunk {
+                    index,
+                    start,
+                    end,
+                    error,
+                }
+            })
+            .collect();
+        return Err(DownloadError::Incomplete {
+            failed,
+            total: chunk_count,
+        });
+    }
+    Ok(Ranged::Complete(layout))
+}
+
+/// How a ranged download ended.
+enum Ranged {
+    /// Every chunk was downloaded; the layout is the saved one when resuming
+    Complete(ChunkLayout),
+    /// A chunk request was answered with the whole file, whose body is
+    /// still unread
+    FullResponse(reqwest::Response),
+}
+
+/// Feeds the completed chunks that follow the data hashed so far into
+/// `digests`, reading them back from `part_path`. A chunk that finishes ahead
+/// of an earlier one waits until that one is done; if another task is already
+/// hashing, it carries on with the chunks that completed meanwhile.
+async fn hash_completed(
+    digests: &AsyncMutex<Digests>,
+    state: &Mutex<ResumeState>,
+    part_path: &Path,
+    layout: ChunkLayout,
+) {
+    let Ok(mut digests) = digests.try_lock() else {
+        return;
+    };
+    loop {
+        let next = {
+            let state = state.lock().unwrap();
+            let hashed = digests.hashed();
+            let i = layout.index_at(hashed);
+            Some(i).filter(|&i| layout.range(i).0 == hashed && state.completed.contains(&i))
+        };
+        let Some(i) = next else {
+            break;
+        };
+        let (start, end) = layout.range(i);
+        // 读取失败时进度无法再对齐分片，之后会在下载完成后整个重新计算
+        if let Err(e) = digests.update_from_file(part_path, start, end).await {
+            log::debug!("Failed to hash chunk {}: {}", i, e);
+            break;
+        }
+    }
+}
+
+/// The URLs chunks of `remote` can be fetched from: its own URL followed by
+/// every mirror that serves a file of the same length with range support.
+/// Mirrors that cannot be used are skipped with a warning; one that reports a
+/// different length fails the download, since it holds a different file.
+async fn chunk_sources(
+    client: &reqwest::Client,
+    opts: &DownloadOptions,
+    remote: &RemoteFile,
+) -> Result<Vec<String>, DownloadError> {
+    let probes = opts.mirrors.iter().map(|mirror| {
+        probe::probe(
+            client_for(opts, client, mirror.trim()),
+            mirror.trim(),
+            opts.timeout,
+        )
+    });
+    let probes = futures::future::join_all(probes).await;
+    let mut urls = vec![remote.url.clone()];
+    for (mirror, result) in opts.mirrors.iter().zip(probes) {
+        match result {
+            Ok(found) if !found.accept_ranges => {
+                log::warn!(
+                    "Skipping mirror {}: it does not support range requests",
+                    mirror
+                )
+            }
+            Ok(found) if found.content_length != remote.content_length => {
+                return Err(DownloadError::MirrorMismatch {
+                    url: mirror.clone(),
+                    expected: remote.content_length.unwrap_or(0),
+                    actual: found.content_length,
+                });
+            }
+            Ok(found) => urls.push(found.url),
+            Err(e) => log::warn!("Skipping mirror {}: {}", mirror, e),
+        }
+    }
+    Ok(urls)
+}
+
+/// Downloads `remote` into `part_path` over a single connection, for servers
+/// that do not support range requests. The body of `response` is read when
+/// one is given, instead of making a new request. Returns the number of
+/// bytes received.
+async fn download_stream(
+    client: &reqwest::Client,
+    opts: &DownloadOptions,
+    remote: &RemoteFile,
+    part_path: &Path,
+    digests: Option<&AsyncMutex<Digests>>,
+    response: Option<reqwest::Response>,
+) -> Result<u64, DownloadError> {
+    let limiter = opts.limit_rate.map(RateLimiter::new);
+    let mut reader = if let Some(response) = response {
+        decode::body_reader(response)?
+    } else if local::is_file_url(&remote.url) {
+        local::open(&remote.url).await?
+    } else {
+        let response = client_for(opts, client, &remote.url)
+            .get(&remote.url)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(DownloadError::Status(response.status()));
+        }
+        decode::body_reader(response)?
+    };
+    let pb = progress_bar(
+        remote.content_length,
+        opts,
+        &bar_label(opts, remote, part_path),
+    );
+    let mut file = if opts.discard {
+        None
+    } else {
+        Some(
+            File::create(part_path)
+                .await
+                .map_err(DownloadError::io("failed to create part file"))?,
+        )
+    };
+    // 单连接下载无法续传，失败或被取消时删除不完整的文件
+    let mut guard = RemoveOnDrop {
+        path: part_path,
+        keep: opts.keep_temp || opts.discard,
+    };
+    // 单连接下载的数据按顺序到达，可以边写边计算哈希
+    let mut digests = match digests {
+        Some(digests) => Some(digests.lock().await),
+        None => None,
+    };
+    if let Some(digests) = &mut digests {
+        digests.reset();
+    }
+    let mut buf = vec![0; 64 * 1024];
+    let mut received = 0;
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .map_err(DownloadError::io("failed to read response body"))?;
+        if n == 0 {
+            break;
+        }
+        if let Some(limiter) = &limiter {
+            limiter.acquire(n as u64).await;
+        }
+        if let Some(file) = &mut file {
+            file.write_all(&buf[..n])
+                .await
+                .map_err(DownloadError::io("failed to write part file"))?;
+        }
+        received += n as u64;
+        // 服务器没有给出大小时，只能在收到的数据超过上限时停下
+        if let Some(limit) = opts.max_size.filter(|&limit| received > limit) {
+            return Err(DownloadError::TooLarge(limit));
+        }
+        if let Some(digests) = &mut digests {
+            digests.update(&buf[..n]);
+        }
+        pb.inc(n as u64);
+        if let Some(quota) = &opts.quota {
+            quota.add(n as u64);
+        }
+        check_quota(opts)?;
+    }
+    pb.finish().await;
+    guard.keep = true;
+    Ok(received)
+}
+
+/// Deletes `path` when dropped unless `keep` has been set.
+struct RemoveOnDrop<'a> {
+    path: &'a Path,
+    keep: bool,
+}
+
+impl Drop for RemoveOnDrop<'_> {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(self.path);
+        }
+    }
+}
+
+fn check_quota(opts: &DownloadOptions) -> Result<(), DownloadError> {
+    match &opts.quota {
+        Some(quota) if quota.exceeded() => Err(DownloadError::QuotaExceeded(quota.limit())),
+        _ => Ok(()),
+    }
+}
+
+/// Fails if the file system holding `dir` has less than `needed` bytes
+/// free. File systems that cannot report their free space are not checked.
+fn check_space(dir: &Path, needed: u64) -> Result<(), DownloadError> {
+    match fs4::available_space(dir) {
+        Ok(available) if available < needed => Err(DownloadError::InsufficientSpace {
+            path: dir.to_path_buf(),
+            needed,
+            available,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => true,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    a == b
+}
+
+/// How long one chunk took in this run, from getting a connection until its
+/// last byte was written, retries included.
+struct ChunkTiming {
+    index: u64,
+    elapsed: Duration,
+    bytes: u64,
+}
+
+/// Logs the fastest, median and slowest chunk in verbose mode, to show
+/// whether a few slow chunks held up the whole download.
+fn report_timings(timings: &mut [ChunkTiming]) {
+    if timings.is_empty() || !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    timings.sort_by_key(|timing| timing.elapsed);
+    let slowest = &timings[timings.len() - 1];
+    log::debug!(
+        "{} chunks took {:.2?} min, {:.2?} median, {:.2?} max (chunk {}, {} at {}/s)",
+        timings.len(),
+        timings[0].elapsed,
+        timings[timings.len() / 2].elapsed,
+        slowest.elapsed,
+        slowest.index,
+        HumanBytes(slowest.bytes),
+        HumanBytes((slowest.bytes as f64 / slowest.elapsed.as_secs_f64().max(f64::EPSILON)) as u64)
+    );
+}
+
+/// Delay before retry number `attempt` (counting from 1): `base` doubled for
+/// every earlier attempt, plus up to `base` of random jitter so chunks that
+/// failed together do not retry in lockstep.
+fn backoff(base: Duration, attempt: u64) -> Duration {
+    let exponent = (attempt.saturating_sub(1)).min(16) as u32;
+    base.saturating_mul(1 << exponent) + base.mul_f64(rand::random::<f64>())
+}
+
+/// Parses a Retry-After header given either in seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// State shared by all chunk requests of one download.
+pub struct ChunkContext {
+    pub client: reqwest::Client,
+    /// Clients for particular hosts, as in `DownloadOptions::host_clients`
+    pub host_clients: HashMap<String, reqwest::Client>,
+    /// Where chunks are fetched from: the file's URL followed by its mirrors
+    pub urls: Vec<String>,
+    /// File the chunks are written into, at their own offsets
+    pub part_path: PathBuf,
+    /// Progress bar advanced as chunk bodies stream in
+    pub pb: Progress,
+    /// Shared bandwidth limit, each write waits for its share
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Shared download quota, charged for every byte received
+    pub quota: Option<Arc<Quota>>,
+    /// Timeout for each chunk request as a whole
+    pub timeout: Option<Duration>,
+    /// Adaptive connection limit, fed with the bytes every chunk receives
+    pub connections: Option<Arc<Connections>>,
+    /// Throw the chunk bodies away instead of writing them into `part_path`
+    pub discard: bool,
+    /// Sent as `If-Range` on chunk requests to the file's own URL when
+    /// resuming, so a server whose file changed answers with the new file
+    /// instead of a range of it. Mirrors have their own validators and never
+    /// get it.
+    pub if_range: Option<String>,
+    /// The first response from the file's own URL that carried the whole
+    /// file instead of the range asked for, kept so its body can be read
+    /// instead of asking again
+    pub full_response: Mutex<Option<reqwest::Response>>,
+}
+
+impl ChunkContext {
+    /// The client for requests to `url`.
+    pub fn client_for(&self, url: &str) -> &reqwest::Client {
+        host_client(&self.host_clients, url).unwrap_or(&self.client)
+    }
+
+    /// The URL for attempt number `attempt` (counting from 0) at chunk
+    /// `index`. Chunks are spread over the mirrors in turn and every retry
+    /// moves on to the next one.
+    pub fn source(&self, index: u64, attempt: u64) -> &str {
+        let i = (index + attempt) % self.urls.len() as u64;
+        &self.urls[i as usize]
+    }
+}
+
+/// Downloads the inclusive byte range `start..=end` of `url` and writes
+/// it into `ctx.part_path` at offset `start`. Returns the number of bytes
+/// written. On failure the bytes already counted on the progress bar are
+/// taken back off so a retry does not count them twice.
+pub async fn download_chunk(
+    ctx: &ChunkContext,
+    url: &str,
+    start: u64,
+    end: u64,
+) -> Result<u64, DownloadError> {
+    let pb = &ctx.pb;
+    let mut written = 0;
+    let started = Instant::now();
+    let result = async {
+        let mut request = ctx
+            .client_for(url)
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start, end));
+        // 镜像的 ETag 与主地址不同，带上主地址的 If-Range 只会换来整个文件
+        if let Some(if_range) = ctx.if_range.as_ref().filter(|_| url == ctx.urls[0]) {
+            request = request.header(IF_RANGE, if_range);
+        }
+        if let Some(timeout) = ctx.timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await?;
+        log::debug!("bytes {}-{}: {}", start, end, response.status());
+        // 请求的范围超出文件大小，说明服务器上的文件已经变了，重试也没有用
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Err(DownloadError::RangeNotSatisfiable { start, end });
+        }
+        // 错误页面的内容不能当作文件数据写入
+        if !response.status().is_success() {
+            return Err(match retry_after(response.headers()) {
+                Some(retry_after) => DownloadError::RetryAfter {
+                    status: response.status(),
+                    retry_after,
+                },
+                None => DownloadError::Status(response.status()),
+            });
+        }
+        // 服务器忽略 Range 返回了整个文件，继续写入会让各分片互相覆盖；
+        // 带了 If-Range 时这也表示文件已经变了，已下载的分片属于旧版本
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            // 镜像上的文件可能是另一个版本，只有主地址的完整响应可以接着读
+            if response.status() == StatusCode::OK && url == ctx.urls[0] {
+                ctx.full_response.lock().unwrap().get_or_insert(response);
+            }
+            return Err(DownloadError::RangeIgnored);
+        }
+        if let Some((first, last, _)) = probe::content_range(response.headers()) {
+            if (first, last) != (start, end) {
+                return Err(DownloadError::UnexpectedRange {
+                    expected: (start, end),
+                    actual: (first, last),
+                });
+            }
+        }
+        let expected = end - start + 1;
+        // 声明的长度与范围不符时，一个字节也不写
+        if let Some(actual) = response.content_length().filter(|&n| n != expected) {
+            return Err(DownloadError::SizeMismatch { expected, actual });
+        }
+        // 各分片使用独立的文件句柄写入 .part 中互不重叠的区域，并发写入是安全的
+        let mut file = if ctx.discard {
+            None
+        } else {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(&ctx.part_path)
+                .await
+                .map_err(DownloadError::io("failed to open part file"))?;
+            file.seek(SeekFrom::Start(start))
+                .await
+                .map_err(DownloadError::io("failed to seek part file"))?;
+            Some(file)
+        };
+        let mut stream = response.bytes_stream();
+        while let Some(bytes) = stream.next().await {
+            let bytes = bytes?;
+            // 多出来的数据会覆盖相邻分片，写入前就要拦下
+            if written + bytes.len() as u64 > expected {
+                return Err(DownloadError::SizeMismatch {
+                    expected,
+                    actual: written + bytes.len() as u64,
+                });
+            }
+            if let Some(limiter) = &ctx.limiter {
+                limiter.acquire(bytes.len() as u64).await;
+            }
+            if let Some(file) = &mut file {
+                file.write_all(&bytes)
+                    .await
+                    .map_err(DownloadError::io("failed to write part file"))?;
+            }
+            written += bytes.len() as u64;
+            pb.inc(bytes.len() as u64);
+            if let Some(quota) = &ctx.quota {
+                quota.add(bytes.len() as u64);
+            }
+            if let Some(connections) = &ctx.connections {
+                connections.record_bytes(bytes.len() as u64);
+            }
+        }
+        if written != expected {
+            return Err(DownloadError::SizeMismatch {
+                expected,
+                actual: written,
+            });
+        }
+        match &mut file {
+            Some(file) => file
+                .flush()
+                .await
+                .map_err(DownloadError::io("failed to write part file")),
+            None => Ok(()),
+        }
+    }
+    .await;
+
+    match &result {
+        Ok(()) => log::debug!("bytes {}-{}: done in {:.2?}", start, end, started.elapsed()),
+        Err(_) => pb.dec(written),
+    }
+    result.map(|()| written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{serve, Request, Response};
+
+    /// A chunk context for `url` that writes into `part_path`.
+    fn chunk_context(url: &str, part_path: &Path) -> ChunkContext {
+        ChunkContext {
+            client: build_client(&DownloadOptions::default()).unwrap(),
+            host_clients: HashMap::new(),
+            urls: vec![url.to_string()],
+            part_path: part_path.to_path_buf(),
+            pb: Progress::new(ProgressBar::hidden(), None),
+            limiter: None,
+            quota: None,
+            timeout: None,
+            connections: None,
+            discard: false,
+            if_range: None,
+            full_response: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn tiny_files_get_one_chunk() {
+        let opts = DownloadOptions::default();
+        for content_length in [1, 2] {
+            let layout = chunk_layout(&opts, content_length);
+            assert_eq!(layout.chunk_count, 1);
+            assert_eq!(layout.range(0), (0, content_length - 1));
+        }
+    }
+
+    #[test]
+    fn empty_files_get_no_chunks() {
+        assert_eq!(ChunkLayout::single(0).chunk_count, 0);
+    }
+
+    #[test]
+    fn small_files_are_not_split() {
+        // 小于 MIN_AUTO_CHUNK_SIZE 的文件只用一个分片
+        let layout = chunk_layout(&DownloadOptions::default(), 100_000);
+        assert_eq!(layout.chunk_count, 1);
+        assert_eq!(layout.range(0), (0, 99_999));
+        let layout = chunk_layout(&DownloadOptions::default(), 2 * MIN_AUTO_CHUNK_SIZE);
+        assert_eq!(layout.chunk_count, 2);
+    }
+
+    #[tokio::test]
+    async fn short_chunk_bodies_are_rejected() {
+        let server = serve(|request| {
+            let (start, end) = request.range().unwrap();
+            // Content-Range 正确，但正文少了一半
+            Response::new(206)
+                .header("Content-Range", format!("bytes {}-{}/20", start, end))
+                .body(vec![b'x'; 5])
+        })
+        .await;
+        let part = NamedTempFile::new().unwrap();
+        std::fs::write(part.path(), [0; 20]).unwrap();
+        let ctx = chunk_context(&server.url, part.path());
+        let result = download_chunk(&ctx, &server.url, 0, 9).await;
+        assert!(
+            matches!(
+                result,
+                Err(DownloadError::SizeMismatch {
+                    expected: 10,
+                    actual: 5
+                })
+            ),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn long_chunk_bodies_do_not_overwrite_the_next_chunk() {
+        let body: Vec<u8> = (1..=20).collect();
+        let server = serve(move |request| {
+            let (start, end) = request.range().unwrap();
+            // 声明的范围正确，正文却一直发到文件末尾
+            Response::partial(&body, start, end).body(&body[start as usize..])
+        })
+        .await;
+        let part = NamedTempFile::new().unwrap();
+        std::fs::write(part.path(), [0; 20]).unwrap();
+        let ctx = chunk_context(&server.url, part.path());
+        let result = download_chunk(&ctx, &server.url, 0, 9).await;
+        assert!(
+            matches!(
+                result,
+                Err(DownloadError::SizeMismatch { expected: 10, .. })
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!(std::fs::read(part.path()).unwrap(), [0; 20]);
+    }
+
+    #[tokio::test]
+    async fn files_without_a_length_are_fetched_in_one_stream() {
+        let body: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let server = serve(move |_| {
+            // 声称支持 Range，但从不告诉文件有多大
+            Response::new(200)
+                .header("Accept-Ranges", "bytes")
+                .body(body.clone())
+                .without_length()
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let opts = DownloadOptions {
+            output: Some(dir.path().to_path_buf()),
+            ..DownloadOptions::default()
+        };
+        let download = download(&format!("{}/stream.bin", server.url), &opts)
+            .await
+            .unwrap();
+        assert_eq!(download.path, dir.path().join("stream.bin"));
+        assert_eq!(download.bytes, expected.len() as u64);
+        assert_eq!(download.chunks, 1);
+        assert_eq!(std::fs::read(&download.path).unwrap(), expected);
+        let gets: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "GET")
+            .collect();
+        assert_eq!(gets.len(), 1);
+        assert_eq!(gets[0].header("range"), None);
+    }
+
+    /// Serves `body` in ranges under `etag`. Like a real server it answers
+    /// with the whole file when `If-Range` names another version.
+    fn ranged(body: Vec<u8>, etag: &'static str) -> impl Fn(&Request) -> Response {
+        move |request| {
+            let changed = request.header("if-range").is_some_and(|v| v != etag);
+            match request.range() {
+                Some((start, end)) if !changed => Response::partial(&body, start, end),
+                _ => Response::new(200).body(body.clone()),
+            }
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", etag)
+        }
+    }
+
+    #[tokio::test]
+    async fn resuming_with_a_mirror_keeps_the_completed_chunks() {
+        let body: Vec<u8> = (0..4 * MIN_CHUNK_SIZE as u32).map(|i| i as u8).collect();
+        let server = serve(ranged(body.clone(), "\"a\"")).await;
+        let mirror = serve(ranged(body.clone(), "\"b\"")).await;
+        let url = format!("{}/file.bin", server.url);
+        let dir = tempfile::tempdir().unwrap();
+        let opts = DownloadOptions {
+            output: Some(dir.path().to_path_buf()),
+            chunk_size: Some(MIN_CHUNK_SIZE),
+            mirrors: vec![format!("{}/file.bin", mirror.url)],
+            ..DownloadOptions::default()
+        };
+
+        // 上次中断时前两个分片已经完成
+        let layout = chunk_layout(&opts, body.len() as u64);
+        let mut state = ResumeState::new(&url, layout, Some("\"a\""), None);
+        state.completed.extend([0, 1]);
+        state.save(&dir.path().join("file.bin.idm")).unwrap();
+        let done = 2 * MIN_CHUNK_SIZE as usize;
+        let mut part = body[..done].to_vec();
+        part.resize(body.len(), 0);
+        std::fs::write(dir.path().join("file.bin.part"), part).unwrap();
+
+        let download = download(&url, &opts).await.unwrap();
+        assert_eq!(std::fs::read(&download.path).unwrap(), body);
+        let gets: Vec<_> = server
+            .requests()
+            .into_iter()
+            .chain(mirror.requests())
+            .filter(|r| r.method == "GET")
+            .collect();
+        let mut ranges: Vec<_> = gets.iter().map(|r| r.range().unwrap()).collect();
+        ranges.sort();
+        assert_eq!(ranges, [layout.range(2), layout.range(3)]);
+        assert!(server
+            .requests()
+            .iter()
+            .filter(|r| r.method == "GET")
+            .all(|r| r.header("if-range") == Some("\"a\"")));
+        assert!(mirror
+            .requests()
+            .iter()
+            .all(|r| r.header("if-range").is_none()));
+    }
+
+    #[tokio::test]
+    async fn a_changed_file_is_downloaded_again_from_the_start() {
+        let old: Vec<u8> = (0..4 * MIN_CHUNK_SIZE as u32).map(|i| i as u8).collect();
+        let new: Vec<u8> = old.iter().map(|b| b ^ 0xff).collect();
+        let version = Arc::new(Mutex::new(1));
+        let server = serve({
+            let version = version.clone();
+            let new = new.clone();
+            move |request| {
+                let (body, etag) = match *version.lock().unwrap() {
+                    1 => (&old, "\"v1\""),
+                    _ => (&new, "\"v2\""),
+                };
+                let response = match request.range() {
+                    // 第一次下载在最后一个分片失败，留下 .part 和 .idm
+                    Some((start, _)) if etag == "\"v1\"" && start >= 3 * MIN_CHUNK_SIZE => {
+                        Response::new(500)
+                    }
+                    Some((start, end)) => Response::partial(body, start, end),
+                    None => Response::new(200).body(body.clone()),
+                };
+                response
+                    .header("Accept-Ranges", "bytes")
+                    .header("ETag", etag)
+            }
+        })
+        .await;
+        let url = format!("{}/file.bin", server.url);
+        let dir = tempfile::tempdir().unwrap();
+        let opts = DownloadOptions {
+            output: Some(dir.path().to_path_buf()),
+            chunk_size: Some(MIN_CHUNK_SIZE),
+            max_retries: 1,
+            retry_passes: 0,
+            retry_wait: Duration::ZERO,
+            ..DownloadOptions::default()
+        };
+
+        assert!(download(&url, &opts).await.is_err());
+        assert!(dir.path().join("file.bin.idm").exists());
+        assert!(dir.path().join("file.bin.part").exists());
+
+        *version.lock().unwrap() = 2;
+        let download = download(&url, &opts).await.unwrap();
+        assert_eq!(std::fs::read(&download.path).unwrap(), new);
+        assert!(!dir.path().join("file.bin.idm").exists());
+        assert!(!dir.path().join("file.bin.part").exists());
+    }
+
+    #[tokio::test]
+    async fn a_part_file_without_a_resume_file_is_kept() {
+        let server = serve(|request| {
+            match request.range() {
+                Some((start, end)) => Response::partial(b"0123456789", start, end),
+                None => Response::new(200).body("0123456789"),
+            }
+            .header("Accept-Ranges", "bytes")
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file.bin.part"), "other").unwrap();
+        let opts = DownloadOptions {
+            output: Some(dir.path().to_path_buf()),
+            ..DownloadOptions::default()
+        };
+        let result = download(&format!("{}/file.bin", server.url), &opts).await;
+        assert!(
+            matches!(result, Err(DownloadError::FileExists(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("file.bin.part")).unwrap(),
+            b"other"
+        );
+    }
+
+    /// Runs `download_ranged` into /dev/full, resuming so the part file is
+    /// not created, with the primary and mirror answering as `answer` says.
+    /// Chunk 0 goes to the primary and chunk 1 to the mirror first.
+    #[cfg(target_os = "linux")]
+    async fn ranged_into_full_disk(
+        answer: fn(mirror: bool, index: u64, body: &[u8], range: (u64, u64)) -> Response,
+    ) -> Result<Ranged, DownloadError> {
+        let body: Vec<u8> = (0..2 * MIN_CHUNK_SIZE as u32).map(|i| i as u8).collect();
+        let server_body = body.clone();
+        let server = serve(move |request| {
+            match request.range() {
+                Some(range) => answer(false, range.0 / MIN_CHUNK_SIZE, &server_body, range),
+                None => Response::new(200).body(server_body.clone()),
+            }
+            .header("Accept-Ranges", "bytes")
+        })
+        .await;
+        let mirror = serve(move |request| {
+            match request.range() {
+                Some(range) => answer(true, range.0 / MIN_CHUNK_SIZE, &body, range),
+                None => Response::new(200).body(body.clone()),
+            }
+            .header("Accept-Ranges", "bytes")
+        })
+        .await;
+        let url = format!("{}/file.bin", server.url);
+        let opts = DownloadOptions {
+            chunk_size: Some(MIN_CHUNK_SIZE),
+            mirrors: vec![format!("{}/file.bin", mirror.url)],
+            retry_wait: Duration::from_secs(10),
+            ..DownloadOptions::default()
+        };
+        let client = build_client(&opts).unwrap();
+        let remote = probe::probe(&client, &url, None).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("file.bin.idm");
+        let layout = chunk_layout(&opts, 2 * MIN_CHUNK_SIZE);
+        ResumeState::new(&url, layout, None, None)
+            .save(&state_path)
+            .unwrap();
+        download_ranged(
+            &client,
+            &url,
+            &opts,
+            &remote,
+            Path::new("/dev/full"),
+            Some(&state_path),
+            None,
+        )
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn a_full_disk_is_reported_after_the_primary_sent_the_whole_file() {
+        // 主地址对分片 0 返回整个文件，分片 1 写入时磁盘已满
+        let result = ranged_into_full_disk(|mirror, index, body, (start, end)| match index {
+            0 if !mirror => Response::new(200).body(body),
+            _ => Response::partial(body, start, end).delay(Duration::from_millis(200)),
+        })
+        .await;
+        assert!(
+            matches!(&result, Err(e) if e.is_disk_full()),
+            "{:?}",
+            result.map(|_| ())
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn a_whole_file_from_a_mirror_is_not_read() {
+        // 镜像对分片 1 返回整个文件，分片 0 写入时磁盘已满
+        let result = ranged_into_full_disk(|mirror, index, body, (start, end)| match index {
+            1 if mirror => Response::new(200).body(body),
+            _ => Response::partial(body, start, end).delay(Duration::from_millis(200)),
+        })
+        .await;
+        assert!(
+            matches!(&result, Err(e) if e.is_disk_full()),
+            "{:?}",
+            result.map(|_| ())
+        );
+    }
+
+    #[tokio::test]
+    async fn host_clients_are_only_used_for_their_host() {
+        let body: Vec<u8> = (0..4 * MIN_CHUNK_SIZE as u32).map(|i| i as u8).collect();
+        let server = serve(ranged(body.clone(), "\"a\"")).await;
+        let mirror = serve(ranged(body.clone(), "\"a\"")).await;
+        // 两个服务器都在本机，用 localhost 和 127.0.0.1 当作两个主机
+        let url = server.url.replace("127.0.0.1", "localhost") + "/file.bin";
+        let with_credentials = DownloadOptions {
+            user: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            ..DownloadOptions::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let opts = DownloadOptions {
+            output: Some(dir.path().to_path_buf()),
+            chunk_size: Some(MIN_CHUNK_SIZE),
+            mirrors: vec![format!("{}/file.bin", mirror.url)],
+            host_clients: HashMap::from([(
+                "localhost".to_string(),
+                build_client(&with_credentials).unwrap(),
+            )]),
+            ..DownloadOptions::default()
+        };
+        let download = download(&url, &opts).await.unwrap();
+        assert_eq!(std::fs::read(&download.path).unwrap(), body);
+        let mirror_requests = mirror.requests();
+        assert!(mirror_requests.iter().any(|r| r.method == "GET"));
+        assert!(mirror_requests
+            .iter()
+            .all(|r| r.header("authorization").is_none()));
+        assert!(server
+            .requests()
+            .iter()
+            .all(|r| r.header("authorization").is_some()));
+    }
+
+    #[tokio::test]
+    async fn host_clients_are_not_used_after_a_redirect_to_another_host() {
+        let body: Vec<u8> = (0..4 * MIN_CHUNK_SIZE as u32).map(|i| i as u8).collect();
+        let files = ranged(body.clone(), "\"a\"");
+        let server = serve(move |request| {
+            if request.path == "/file.bin" {
+                let host = request
+                    .header("host")
+                    .unwrap()
+                    .replace("localhost", "127.0.0.1");
+                Response::new(302).header("Location", format!("http://{}/real.bin", host))
+            } else {
+                files(request)
+            }
+        })
+        .await;
+        let url = server.url.replace("127.0.0.1", "localhost") + "/file.bin";
+        let with_credentials = DownloadOptions {
+            user: Some("user".to_string()),
+            ..DownloadOptions::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let opts = DownloadOptions {
+            output: Some(dir.path().to_path_buf()),
+            chunk_size: Some(MIN_CHUNK_SIZE),
+            host_clients: HashMap::from([(
+                "localhost".to_string(),
+                build_client(&with_credentials).unwrap(),
+            )]),
+            ..DownloadOptions::default()
+        };
+        let download = download(&url, &opts).await.unwrap();
+        assert_eq!(std::fs::read(&download.path).unwrap(), body);
+        for request in server.requests() {
+            let authorized = request.header("authorization").is_some();
+            assert_eq!(authorized, request.path == "/file.bin", "{:?}", request);
+        }
+    }
+}
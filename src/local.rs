@@ -0,0 +1,48 @@
+use crate::{DownloadError, RemoteFile};
+use reqwest::header::HeaderMap;
+use reqwest::{StatusCode, Url};
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+
+/// Whether `url` names a local file, which is copied instead of downloaded.
+pub fn is_file_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.scheme() == "file")
+}
+
+fn file_path(url: &str) -> Result<PathBuf, DownloadError> {
+    Url::parse(url)
+        .ok()
+        .and_then(|parsed| parsed.to_file_path().ok())
+        .ok_or_else(|| DownloadError::InvalidFileUrl(url.to_string()))
+}
+
+/// Describes the local file at `url` the way a server would, so it goes
+/// through the same naming, size and overwrite checks as a download. Ranges
+/// are not offered, so it is copied in one stream.
+pub fn probe(url: &str) -> Result<RemoteFile, DownloadError> {
+    let metadata = std::fs::metadata(file_path(url)?)
+        .map_err(DownloadError::io("failed to read local file"))?;
+    if !metadata.is_file() {
+        return Err(DownloadError::InvalidFileUrl(url.to_string()));
+    }
+    Ok(RemoteFile {
+        url: url.to_string(),
+        status: StatusCode::OK,
+        content_length: Some(metadata.len()),
+        accept_ranges: false,
+        etag: None,
+        last_modified: metadata.modified().ok(),
+        content_type: None,
+        content_encoding: None,
+        headers: HeaderMap::new(),
+    })
+}
+
+/// Opens the local file at `url` for copying.
+pub async fn open(url: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>, DownloadError> {
+    let file = tokio::fs::File::open(file_path(url)?)
+        .await
+        .map_err(DownloadError::io("failed to open local file"))?;
+    Ok(Box::pin(file))
+}
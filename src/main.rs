@@ -1,221 +1,1529 @@
-use clap::Parser;
-use futures::future::join_all;
-use indicatif::{ProgressBar, ProgressStyle};
-use percent_encoding::percent_decode;
-use reqwest::header::CONTENT_LENGTH;
-use reqwest::header::RANGE;
-use reqwest::Url;
-use std::path::Path;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use tempfile::tempdir;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use futures::stream::{self, StreamExt};
+use idownloader::{
+    Algorithm, BenchmarkResult, CookieJar, Download, DownloadError, DownloadOptions, IpVersion,
+    Manifest, MetalinkFile, Netrc, Plan, Quota, RemoteFile,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::LevelFilter;
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+mod config;
+mod extract;
+#[cfg(feature = "notify")]
+mod notify;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// URL to download
-    url: String,
+    /// URLs to download, `file://` URLs to copy, or Metalink files (.meta4, .metalink)
+    #[arg(required_unless_present_any = ["input_file", "from_manifest"])]
+    urls: Vec<String>,
+
+    /// Read URLs from a file, one per line, or `-` for stdin
+    #[arg(short, long, value_name = "PATH")]
+    input_file: Option<PathBuf>,
 
-    /// Output directory
+    /// Output directory, or `-` to write to stdout
     #[arg(short, long, value_name = "DIR")]
     output: Option<PathBuf>,
 
+    /// Read defaults from this file instead of ~/.config/idownloader/config.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Directory for partial downloads, moved to the output directory when done
+    #[arg(long, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+
+    /// Keep partial files when a download fails and print where they are
+    #[arg(long)]
+    keep_temp: bool,
+
+    /// Do not check for enough free disk space before downloading
+    #[arg(long)]
+    no_space_check: bool,
+
     /// Maximum number of chunks
-    #[arg(short, long, default_value_t = 500, value_name = "NUM")]
+    #[arg(short, long, default_value_t = 500, value_name = "NUM", value_parser = clap::value_parser!(u64).range(1..))]
     max_chunks: u64,
 
+    /// Size of each chunk, e.g. 4m (at least 64k); overrides --max-chunks
+    #[arg(short = 's', long, value_name = "SIZE", value_parser = parse_chunk_size, conflicts_with = "max_chunks")]
+    chunk_size: Option<u64>,
+
     /// Maximum number of retries
     #[arg(short = 'r', long, default_value_t = 3, value_name = "NUM")]
     max_retries: u64, // 添加最大重试次数参数
+
+    /// Maximum number of concurrent connections
+    #[arg(
+        short = 'c',
+        long,
+        default_value_t = 16,
+        value_name = "NUM",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_connections: usize,
+
+    /// Start with a few connections and adjust the count as the download runs, up to -c
+    #[arg(long, conflicts_with = "auto_tune")]
+    adaptive: bool,
+
+    /// Another URL serving the same file to fetch chunks from, can be repeated
+    #[arg(long = "mirror", value_name = "URL")]
+    mirrors: Vec<String>,
+
+    /// Verify the downloaded file against this SHA-256 digest
+    #[arg(long, value_name = "HEX")]
+    sha256: Option<String>,
+
+    /// Verify the downloaded file against this BLAKE3 digest
+    #[arg(long, value_name = "HEX")]
+    blake3: Option<String>,
+
+    /// Verify against the .sha256, .sha1 or .md5 file published next to the file
+    #[arg(long)]
+    verify_sidecar: bool,
+
+    /// Fail if the server sends an HTML page for a URL that is not one
+    #[arg(long)]
+    fail_on_html: bool,
+
+    /// Fail unless the server reports this Content-Type, e.g. application/zip or image/*
+    #[arg(long, value_name = "MIME")]
+    expect_content_type: Option<String>,
+
+    /// Print the file's sha256, sha1, md5 or blake3 digest after downloading
+    #[arg(long, value_name = "ALGO", value_parser = parse_algorithm)]
+    print_hash: Option<Algorithm>,
+
+    /// Extra request header, can be given multiple times
+    #[arg(short = 'H', long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// User name for HTTP Basic authentication
+    #[arg(short, long, value_name = "USER")]
+    user: Option<String>,
+
+    /// Password for HTTP Basic authentication, prompted for if omitted
+    #[arg(short, long, value_name = "PASS", requires = "user")]
+    password: Option<String>,
+
+    /// Bearer token for the Authorization header
+    #[arg(long, value_name = "TOKEN", conflicts_with_all = ["user", "password"])]
+    bearer: Option<String>,
+
+    /// Read credentials from this file instead of ~/.netrc when --user is not given
+    #[arg(long, value_name = "FILE")]
+    netrc_file: Option<PathBuf>,
+
+    /// Proxy URL (http, https or socks5), defaults to HTTP_PROXY/HTTPS_PROXY
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Limit the total download speed, e.g. 500k or 2m (bytes per second)
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Timeout for each chunk request in seconds, 0 for none
+    #[arg(long, default_value_t = 300, value_name = "SECS")]
+    timeout: u64,
+
+    /// Timeout for establishing a connection in seconds, 0 for none
+    #[arg(long, default_value_t = 30, value_name = "SECS")]
+    connect_timeout: u64,
+
+    /// Follow at most this many redirects per request, 0 to not follow any
+    #[arg(long, default_value_t = 10, value_name = "NUM")]
+    max_redirects: usize,
+
+    /// Only connect to IPv4 addresses
+    #[arg(short = '4', long, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only connect to IPv6 addresses
+    #[arg(short = '6', long)]
+    ipv6: bool,
+
+    /// Connect to ADDR for HOST, keeping its Host header and TLS name; can be given multiple times
+    #[arg(long, value_name = "HOST:PORT:ADDR", value_parser = parse_resolve)]
+    resolve: Vec<(String, Vec<IpAddr>)>,
+
+    /// Do not verify TLS certificates (insecure, for testing only)
+    #[arg(short = 'k', long)]
+    insecure: bool,
+
+    /// Also trust the root certificates in this PEM file, can be repeated
+    #[arg(long, value_name = "PEM")]
+    cacert: Vec<PathBuf>,
+
+    /// Client certificate (PEM) for servers that require mutual TLS
+    #[arg(long, value_name = "PEM")]
+    cert: Option<PathBuf>,
+
+    /// Private key (PEM) for --cert, if it is not in the same file
+    #[arg(long, value_name = "KEY", requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Overwrite the output file if it already exists
+    #[arg(short, long)]
+    force: bool,
+
+    /// Save as `name (1).ext` etc. if the output file already exists
+    #[arg(long, conflicts_with = "force")]
+    auto_rename: bool,
+
+    /// Keep non-ASCII characters in file names taken from the URL
+    #[arg(long)]
+    preserve_unicode: bool,
+
+    /// Save into the URL's host and directories under the output directory
+    #[arg(short = 'x', long)]
+    force_directories: bool,
+
+    /// Leave out this many leading URL directories with --force-directories
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_name = "NUM",
+        requires = "force_directories"
+    )]
+    cut_dirs: usize,
+
+    /// Do not set the file's modification time from Last-Modified
+    #[arg(long)]
+    no_timestamp: bool,
+
+    /// Only download files that are newer than the local copy
+    #[arg(short = 'N', long, conflicts_with_all = ["force", "auto_rename"])]
+    timestamping: bool,
+
+    /// Number of files to download at the same time
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_name = "NUM",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrent_files: usize,
+
+    /// When downloading several files, also show one bar for all of them
+    #[arg(long)]
+    total_progress: bool,
+
+    /// Print a plain progress line now and then instead of the live bars, e.g. for CI logs
+    #[arg(long, conflicts_with_all = ["quiet", "json"])]
+    no_progress: bool,
+
+    /// Seconds between the lines printed with --no-progress, 0 for none at all
+    #[arg(
+        long,
+        default_value_t = 10,
+        value_name = "SECS",
+        requires = "no_progress"
+    )]
+    progress_interval: u64,
+
+    /// indicatif template for the progress bars, e.g. "{prefix} {bar:40} {percent}%"
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_progress_template)]
+    progress_template: Option<String>,
+
+    /// Characters to draw the progress bars with, from filled to empty, e.g. "=> "
+    #[arg(long, value_name = "CHARS", value_parser = parse_progress_chars)]
+    progress_chars: Option<String>,
+
+    /// User-Agent header to send
+    #[arg(short = 'A', long, default_value = idownloader::DEFAULT_USER_AGENT, value_name = "STRING")]
+    user_agent: String,
+
+    /// Cookie to send, as `name=value`; can be given multiple times
+    #[arg(short = 'b', long = "cookie", value_name = "NAME=VALUE", value_parser = parse_cookie)]
+    cookies: Vec<String>,
+
+    /// Load cookies from a Netscape-format cookie file
+    #[arg(long, value_name = "FILE")]
+    load_cookies: Option<PathBuf>,
+
+    /// Write the cookies in effect after the downloads to a Netscape-format file
+    #[arg(long, value_name = "FILE")]
+    save_cookies: Option<PathBuf>,
+
+    /// Base wait between retries in milliseconds, doubled on each retry
+    #[arg(long, default_value_t = 1000, value_name = "MS")]
+    retry_wait: u64,
+
+    /// Extra rounds of retrying chunks that used up their retries
+    #[arg(long, default_value_t = 1, value_name = "NUM")]
+    retry_passes: u64,
+
+    /// Stop after downloading this much in total, e.g. 500m or 2g
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_limit)]
+    quota: Option<u64>,
+
+    /// Refuse files larger than this, e.g. 500m or 2g
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_limit)]
+    max_size: Option<u64>,
+
+    /// Ask before downloading files larger than this
+    #[arg(long, default_value = "1g", value_name = "SIZE", value_parser = parse_byte_limit)]
+    confirm_size: u64,
+
+    /// Do not ask before downloading large files
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Ask before large downloads even when stdin is not a terminal
+    #[arg(long, conflicts_with = "yes")]
+    interactive: bool,
+
+    /// Only check that the URLs exist and print what the server reports
+    #[arg(long, conflicts_with = "json")]
+    spider: bool,
+
+    /// Print where each file would be saved and how it would be split, then exit
+    #[arg(long, conflicts_with_all = ["spider", "json"])]
+    dry_run: bool,
+
+    /// Time the first few MiB at several connection counts, print the results, then exit
+    #[arg(long, conflicts_with_all = ["spider", "dry_run", "json", "auto_tune"])]
+    benchmark: bool,
+
+    /// Benchmark each file first and download it with the fastest connection count
+    #[arg(long, conflicts_with = "max_connections")]
+    auto_tune: bool,
+
+    /// Download everything but throw the data away, to measure the network speed
+    #[arg(long, conflicts_with_all = [
+        "spider", "dry_run", "benchmark", "sha256", "blake3", "verify_sidecar",
+        "print_hash", "exec", "extract",
+    ])]
+    discard: bool,
+
+    /// Write the server's response headers to <FILE>.headers
+    #[arg(long)]
+    save_headers: bool,
+
+    /// Print the server's response headers to stderr
+    #[arg(long)]
+    dump_headers: bool,
+
+    /// Write the URL, size, chunk layout, SHA-256 and headers to <FILE>.manifest.json
+    #[arg(long, conflicts_with = "discard")]
+    manifest: bool,
+
+    /// Repeat the download described by a manifest, with the same chunks and checks
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["max_chunks", "chunk_size", "sha256"])]
+    from_manifest: Vec<PathBuf>,
+
+    /// Run this shell command on each downloaded file, `{}` is replaced by its path
+    #[arg(long, value_name = "CMD")]
+    exec: Option<String>,
+
+    /// Unpack downloaded .zip, .tar, .tar.gz and .tar.xz archives, then delete them
+    #[arg(long)]
+    extract: bool,
+
+    /// Unpack archives into this directory instead of the one they were saved in
+    #[arg(long, value_name = "DIR", requires = "extract")]
+    extract_to: Option<PathBuf>,
+
+    /// Keep archives after unpacking them
+    #[arg(long, requires = "extract")]
+    keep_archive: bool,
+
+    /// Show a desktop notification when the download finishes or fails
+    #[cfg(feature = "notify")]
+    #[arg(long)]
+    notify: bool,
+
+    /// Only print errors, without a progress bar
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print one JSON object per URL instead of the progress bar and messages
+    #[arg(long)]
+    json: bool,
+
+    /// Also log the range, status and timing of every chunk request
+    #[arg(short, long)]
+    verbose: bool,
 }
-fn extract_filename(url: &str, headers: &reqwest::header::HeaderMap) -> String {
-    // 首先尝试从 Content-Disposition 头中获取文件名
-    if let Some(content_disposition) = headers.get("content-disposition") {
-        if let Ok(content_disposition_str) = content_disposition.to_str() {
-            if let Some(filename) = content_disposition_str
-                .split(';')
-                .find_map(|part| part.trim().strip_prefix("filename="))
-            {
-                return filename.to_string();
-            }
+
+/// Whether a command line argument names a local Metalink file rather than a
+/// URL.
+fn is_metalink(arg: &str) -> bool {
+    let lower = arg.to_ascii_lowercase();
+    !lower.contains("://") && (lower.ends_with(".meta4") || lower.ends_with(".metalink"))
+}
+
+fn read_metalink(path: &Path) -> Result<Vec<MetalinkFile>, String> {
+    let xml = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    idownloader::parse_metalink(&xml).map_err(|e| e.to_string())
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest, String> {
+    let json = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    idownloader::parse_manifest(&json).map_err(|e| e.to_string())
+}
+
+/// Reads one URL per line, skipping blank lines and `#` comments.
+fn read_url_list(path: &Path) -> io::Result<Vec<String>> {
+    let text = if path == Path::new("-") {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(path)?
+    };
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Formats a byte count with binary units, e.g. `1.50 MiB`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", size, UNITS[unit])
+}
+
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn parse_chunk_size(s: &str) -> Result<u64, String> {
+    match idownloader::parse_size(s) {
+        Some(size) if size >= idownloader::MIN_CHUNK_SIZE => Ok(size),
+        Some(_) => Err(format!(
+            "chunk size must be at least {} bytes",
+            idownloader::MIN_CHUNK_SIZE
+        )),
+        None => Err(format!("expected a size like 512k or 4m, got `{}`", s)),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    match idownloader::parse_size(s) {
+        Some(rate) if rate > 0 => Ok(rate),
+        _ => Err(format!("expected a rate like 500k or 2m, got `{}`", s)),
+    }
+}
+
+/// Template keys indicatif knows; anything else would silently render as
+/// nothing.
+const PROGRESS_KEYS: &[&str] = &[
+    "bar",
+    "wide_bar",
+    "spinner",
+    "prefix",
+    "msg",
+    "wide_msg",
+    "pos",
+    "human_pos",
+    "len",
+    "human_len",
+    "percent",
+    "percent_precise",
+    "bytes",
+    "total_bytes",
+    "bytes_per_sec",
+    "binary_bytes",
+    "binary_total_bytes",
+    "binary_bytes_per_sec",
+    "decimal_bytes",
+    "decimal_total_bytes",
+    "decimal_bytes_per_sec",
+    "elapsed",
+    "elapsed_precise",
+    "eta",
+    "eta_precise",
+    "duration",
+    "duration_precise",
+    "per_sec",
+];
+
+fn parse_progress_template(s: &str) -> Result<String, String> {
+    ProgressStyle::default_bar()
+        .template(s)
+        .map_err(|e| format!("malformed template: {}", e))?;
+    // {{ 是转义的花括号，不是占位符
+    let mut rest = s.replace("{{", "");
+    while let Some(start) = rest.find('{') {
+        let key = &rest[start + 1..];
+        let Some(end) = key.find([':', '}']) else {
+            return Err("unclosed `{`".to_string());
+        };
+        if !PROGRESS_KEYS.contains(&&key[..end]) {
+            return Err(format!(
+                "unknown key `{}`, expected one of {}",
+                &key[..end],
+                PROGRESS_KEYS.join(", ")
+            ));
         }
+        rest = key[end..].to_string();
+    }
+    Ok(s.to_string())
+}
+
+fn parse_progress_chars(s: &str) -> Result<String, String> {
+    use unicode_width::UnicodeWidthChar;
+    let mut widths = s.chars().map(|c| c.width().unwrap_or(0));
+    let first = widths.next();
+    if s.chars().count() < 2 || first == Some(0) {
+        return Err("expected at least two visible characters".to_string());
+    }
+    if widths.any(|width| Some(width) != first) {
+        return Err("all characters must be the same width".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_byte_limit(s: &str) -> Result<u64, String> {
+    match idownloader::parse_size(s) {
+        Some(limit) if limit > 0 => Ok(limit),
+        _ => Err(format!("expected a size like 500m or 2g, got `{}`", s)),
     }
+}
 
-    // 如果没有找到，再从URL路径中提取文件名
-    let parsed = Url::parse(url).ok();
-    let path = parsed.as_ref().and_then(|u| Some(u.path()));
+fn parse_algorithm(s: &str) -> Result<Algorithm, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "sha256" => Ok(Algorithm::Sha256),
+        "sha1" => Ok(Algorithm::Sha1),
+        "md5" => Ok(Algorithm::Md5),
+        "blake3" => Ok(Algorithm::Blake3),
+        _ => Err("expected sha256, sha1, md5 or blake3".to_string()),
+    }
+}
 
-    let (base, ext) = path
-        .map(|p| {
-            let path = Path::new(p);
-            let stem = path
-                .file_stem()
-                .and_then(|s| s.to_str())
-                .unwrap_or_default();
-            let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("bin");
-            (stem, ext)
+/// Parses curl's `HOST:PORT:ADDR[,ADDR]...` form. IPv6 addresses may be put
+/// in brackets. The port is checked but not used: the addresses are used for
+/// `HOST` on whatever port the URL names.
+fn parse_resolve(s: &str) -> Result<(String, Vec<IpAddr>), String> {
+    let syntax = || format!("expected `HOST:PORT:ADDR`, got `{}`", s);
+    let (host, rest) = s.split_once(':').ok_or_else(syntax)?;
+    let (port, addrs) = rest.split_once(':').ok_or_else(syntax)?;
+    if host.is_empty() {
+        return Err(syntax());
+    }
+    port.parse::<u16>()
+        .map_err(|_| format!("invalid port `{}`", port))?;
+    let addrs = addrs
+        .split(',')
+        .map(|addr| {
+            let addr = addr.trim();
+            let ip = addr
+                .strip_prefix('[')
+                .and_then(|a| a.strip_suffix(']'))
+                .unwrap_or(addr);
+            ip.parse()
+                .map_err(|_| format!("invalid IP address `{}`", addr))
         })
-        .unwrap_or_else(|| ("download", "bin"));
+        .collect::<Result<_, _>>()?;
+    Ok((host.to_ascii_lowercase(), addrs))
+}
 
-    let host_name = parsed
-        .as_ref()
-        .and_then(|u| u.host_str())
-        .unwrap_or("download");
+fn parse_cookie(s: &str) -> Result<String, String> {
+    match s.split_once('=') {
+        Some((name, _)) if !name.trim().is_empty() => Ok(s.trim().to_string()),
+        _ => Err(format!("expected `name=value`, got `{}`", s)),
+    }
+}
+
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected `Name: Value`, got `{}`", s))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name `{}`", name.trim()))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value for header `{}`", name))?;
+    Ok((name, value))
+}
+
+/// One line of `--json` output.
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    url: &'a str,
+    /// `ok`, `skipped` or `error`
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// Chunks that could not be downloaded, for an incomplete download
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed_ranges: Vec<JsonRange<'a>>,
+}
 
-    let safe_name = percent_decode(base.as_bytes())
-        .decode_utf8_lossy()
-        .replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
-        .trim_end_matches('_')
-        .to_string();
+#[derive(Serialize)]
+struct JsonRange<'a> {
+    chunk: u64,
+    start: u64,
+    end: u64,
+    error: Option<&'a str>,
+}
+
+/// One line per chunk of an incomplete download, e.g.
+/// `bytes 0-1048575 (chunk 0): server returned status code 503`.
+fn failed_chunk_lines(e: &DownloadError) -> impl Iterator<Item = String> + '_ {
+    e.failed_chunks().iter().map(|c| {
+        format!(
+            "bytes {}-{} (chunk {}): {}",
+            c.start,
+            c.end,
+            c.index,
+            c.error.as_deref().unwrap_or("not attempted")
+        )
+    })
+}
 
-    if safe_name.is_empty() {
-        format!("{}.{}", host_name.replace('.', "_"), ext)
+/// Describes what the server reported for `--spider`, e.g.
+/// `200 OK, 3.00 MiB, text/plain, ranges supported`.
+fn describe_remote(remote: &RemoteFile) -> String {
+    let size = match remote.content_length {
+        Some(length) => human_size(length),
+        None => "unknown size".to_string(),
+    };
+    let ranges = if remote.accept_ranges {
+        "ranges supported"
     } else {
-        format!("{}.{}", safe_name, ext)
+        "no ranges"
+    };
+    format!(
+        "{}, {}, {}, {}",
+        remote.status,
+        size,
+        remote.content_type.as_deref().unwrap_or("unknown type"),
+        ranges
+    )
+}
+
+fn print_plan(url: &str, plan: &Plan) {
+    println!("{}", url);
+    if plan.url != url {
+        println!("  redirects to: {}", plan.url);
+    }
+    println!("  save as:      {}", plan.path.display());
+    match plan.size {
+        Some(size) => println!("  size:         {} ({} bytes)", human_size(size), size),
+        None => println!("  size:         unknown"),
+    }
+    println!(
+        "  ranges:       {}",
+        if plan.accept_ranges {
+            "supported"
+        } else {
+            "not supported"
+        }
+    );
+    match (plan.chunks, plan.chunk_size) {
+        (0, _) => println!("  chunks:       none, the file is empty"),
+        (chunks, Some(size)) => println!("  chunks:       {} of {}", chunks, human_size(size)),
+        _ => println!("  chunks:       1, a single stream"),
     }
 }
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-
-    let url = args.url.trim();
-
-    let client = reqwest::Client::new();
-    let head_response = client
-        .head(url)
-        .send()
-        .await
-        .expect("Send head request failed");
-
-    if !head_response.status().is_success() {
-        eprintln!(
-            "Failed to download: Server returned status code {}",
-            head_response.status()
+
+/// Prints the `--benchmark` table for `url` and the connection count that
+/// did best.
+fn print_benchmark(url: &str, levels: &[BenchmarkResult]) {
+    println!("{}", url);
+    println!("  connections  throughput");
+    for level in levels {
+        println!(
+            "  {:>11}  {}/s",
+            level.connections,
+            human_size(level.bytes_per_sec() as u64)
         );
-        return;
-    }
-    let accept_ranges = head_response
-        .headers()
-        .get("accept-ranges")
-        .and_then(|v| v.to_str().ok());
-
-    if accept_ranges == Some("bytes") {
-        let content_length_header = head_response
-            .headers()
-            .get(CONTENT_LENGTH)
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("0");
-        let content_length: u64 = content_length_header
-            .parse()
-            .expect("Invalid content length");
-        let chunk_count = args.max_chunks.min(content_length as u64);
-        println!("Will split into {} chunks", chunk_count);
-        let chunk_size = content_length / chunk_count;
-        let mut tasks = Vec::new();
-        let filename = extract_filename(url, &head_response.headers());
-
-        // Determine the output directory
-        let output_dir = args.output.unwrap_or_else(|| {
-            let home_dir = dirs::download_dir().expect("Failed to get download directory");
-            home_dir
-        });
-        let file_path = output_dir.join(&filename);
-
-        let temp_dir = tempdir().expect("Create temp dir failed");
-        let temp_files: Arc<Mutex<Vec<Option<PathBuf>>>> =
-            Arc::new(Mutex::new(vec![None; chunk_count as usize]));
-        let pb = Arc::new(ProgressBar::new(content_length));
-        pb.set_style(ProgressStyle::default_bar()
-             .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-             .unwrap()
-             .progress_chars("#>-"));
-
-        for i in 0..chunk_count {
-            let start = i * chunk_size;
-            let end = if i == chunk_count - 1 {
-                content_length - 1
+    }
+    if let Some(best) = idownloader::best_level(levels) {
+        println!("  recommended: --max-connections {}", best.connections);
+    }
+}
+
+/// Prints the digest for `--print-hash` in the `HASH  filename` format of
+/// sha256sum, on stderr when the file itself went to stdout.
+fn print_hash(download: &Download) {
+    if let Some(hash) = &download.hash {
+        if download.path == Path::new("-") {
+            eprintln!("{}  -", hash);
+        } else {
+            println!("{}  {}", hash, download.path.display());
+        }
+    }
+}
+
+fn print_json(url: &str, result: &Result<Download, DownloadError>, elapsed: Duration) {
+    let mut report = JsonReport {
+        url,
+        status: "error",
+        filename: None,
+        path: None,
+        bytes: None,
+        chunks: None,
+        hash: None,
+        duration_ms: elapsed.as_millis() as u64,
+        message: None,
+        failed_ranges: Vec::new(),
+    };
+    match result {
+        Ok(download) => {
+            report.status = if download.skipped { "skipped" } else { "ok" };
+            report.filename = download
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned());
+            report.path = Some(download.path.display().to_string());
+            report.bytes = Some(download.bytes);
+            report.chunks = Some(download.chunks);
+            report.hash = download.hash.clone();
+        }
+        Err(e) => {
+            report.message = Some(e.to_string());
+            report.failed_ranges = e
+                .failed_chunks()
+                .iter()
+                .map(|c| JsonRange {
+                    chunk: c.index,
+                    start: c.start,
+                    end: c.end,
+                    error: c.error.as_deref(),
+                })
+                .collect();
+        }
+    }
+    println!("{}", serde_json::to_string(&report).unwrap());
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    // 用法错误返回 1 而不是 clap 默认的 2，2 留给网络错误
+    let parsed = Args::command()
+        .try_get_matches()
+        .and_then(|matches| Ok((Args::from_arg_matches(&matches)?, matches)));
+    let (mut args, matches) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::FAILURE
             } else {
-                (i + 1) * chunk_size - 1
+                ExitCode::SUCCESS
             };
-            let client = client.clone();
-            let url = url.to_string();
-            let temp_path = temp_dir.path().join(format!("part{}", i));
-            let pb = pb.clone();
-            let temp_files_clone = temp_files.clone(); // 克隆 Arc<Mutex>
-            let index = i as usize;
-            let max_retries = args.max_retries; // 获取最大重试次数
-            tasks.push(tokio::spawn(async move {
-                let mut retries = 0;
-                while retries < max_retries {
-                    // 使用新参数控制重试次数
-                    match download_chunk(&client, &url, start, end, &temp_path).await {
-                        Ok(bytes) => {
-                            pb.inc(bytes.len() as u64);
-                            let mut temp_files_lock = temp_files_clone.lock().unwrap();
-                            (*temp_files_lock)[index] = Some(temp_path);
-                            break;
-                        }
-                        Err(e) => {
-                            retries += 1;
-                            eprintln!(
-                                "Error downloading chunk {}: {}. Retrying ({}/{})...",
-                                i, e, retries, max_retries
-                            );
-                            if retries == max_retries {
-                                eprintln!(
-                                    "Failed to download chunk {} after {} retries",
-                                    i, max_retries
-                                );
-                            }
-                        }
-                    }
+        }
+    };
+    let level = if args.quiet {
+        LevelFilter::Error
+    } else if args.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    // 只输出本程序的日志，RUST_LOG 可以覆盖默认级别
+    env_logger::Builder::new()
+        .filter_module("idownloader", level)
+        .parse_default_env()
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+    // 命令行上没有给出的参数使用配置文件中的值
+    if let Err(e) = config::load(args.config.as_deref()).and_then(|c| c.apply(&mut args, &matches))
+    {
+        eprintln!("error: {}", e);
+        return ExitCode::FAILURE;
+    }
+    if let Some(path) = &args.input_file {
+        match read_url_list(path) {
+            Ok(urls) => args.urls.extend(urls),
+            Err(e) => {
+                eprintln!("error: failed to read {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    // Metalink 文件展开为其中每个文件的首选地址，其余地址作为镜像
+    let mut metalinks = HashMap::new();
+    for arg in std::mem::take(&mut args.urls) {
+        if !is_metalink(&arg) {
+            args.urls.push(arg);
+            continue;
+        }
+        match read_metalink(Path::new(&arg)) {
+            Ok(files) => {
+                for file in files {
+                    args.urls.push(file.urls[0].clone());
+                    metalinks.insert(file.urls[0].clone(), file);
                 }
-            }));
-        }
-
-        join_all(tasks).await;
-
-        // 解锁 temp_files 并合并文件
-        let temp_files_final = temp_files.lock().unwrap();
-        let mut file = File::create(&file_path).await.expect("Create file failed");
-        for (i, temp_path) in temp_files_final.iter().enumerate() {
-            if let Some(path) = temp_path {
-                let mut temp_file = File::open(path).await.expect("Open temp file failed");
-                let mut buffer = Vec::new();
-                temp_file
-                    .read_to_end(&mut buffer)
-                    .await
-                    .expect("Read temp file failed");
-                file.write_all(&buffer).await.expect("Write file failed");
-            } else {
-                eprintln!("Skipping chunk {} as it failed to download", i);
+            }
+            Err(e) => {
+                eprintln!("error: failed to read {}: {}", arg, e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    // 清单记录了原始地址，重新下载时沿用当时的分片方式和校验值
+    let mut manifests = HashMap::new();
+    for path in &args.from_manifest {
+        match read_manifest(path) {
+            Ok(manifest) => {
+                args.urls.push(manifest.url.clone());
+                manifests.insert(manifest.url.clone(), manifest);
+            }
+            Err(e) => {
+                eprintln!("error: failed to read {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
             }
         }
+    }
+    if args.urls.is_empty() {
+        eprintln!("error: no URLs to download");
+        return ExitCode::FAILURE;
+    }
+    if args.urls.len() > 1 && args.sha256.is_some() {
+        eprintln!("error: --sha256 can only be used with a single URL");
+        return ExitCode::FAILURE;
+    }
+    if args.urls.len() > 1 && args.blake3.is_some() {
+        eprintln!("error: --blake3 can only be used with a single URL");
+        return ExitCode::FAILURE;
+    }
 
-        temp_dir.close().expect("Remove temp dir failed");
-        println!("Download complete!");
-        println!("File saved at: {}", file_path.display());
+    // 只给了用户名时在终端提示输入密码，输入内容不回显
+    let password = match (&args.user, args.password) {
+        (Some(_), None) => match rpassword::prompt_password("Password: ") {
+            Ok(password) => Some(password),
+            Err(e) => {
+                eprintln!("error: failed to read password: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        (_, password) => password,
+    };
+    // 输出到 stdout 时，提示信息改为输出到 stderr，避免混入文件内容
+    let stdout = args.output.as_deref() == Some(Path::new("-"));
+    if stdout && args.concurrent_files > 1 {
+        eprintln!("error: --concurrent-files cannot be used when writing to stdout");
+        return ExitCode::FAILURE;
+    }
+    if !args.mirrors.is_empty() && args.urls.len() > 1 {
+        eprintln!("error: --mirror can only be used with a single URL");
+        return ExitCode::FAILURE;
+    }
+    if stdout && args.json {
+        eprintln!("error: --json cannot be used when writing to stdout");
+        return ExitCode::FAILURE;
+    }
+    if stdout && args.exec.is_some() {
+        eprintln!("error: --exec cannot be used when writing to stdout");
+        return ExitCode::FAILURE;
+    }
+    if stdout && args.extract {
+        eprintln!("error: --extract cannot be used when writing to stdout");
+        return ExitCode::FAILURE;
+    }
+    if stdout && args.discard {
+        eprintln!("error: --discard cannot be used when writing to stdout");
+        return ExitCode::FAILURE;
+    }
+    if stdout && args.manifest {
+        eprintln!("error: --manifest cannot be used when writing to stdout");
+        return ExitCode::FAILURE;
+    }
+    let info = |msg: String| {
+        if args.quiet || args.json {
+            return;
+        }
+        if stdout {
+            eprintln!("{}", msg);
+        } else {
+            println!("{}", msg);
+        }
+    };
+    // 下载成功后先执行 --exec 再解压，这样命令看到的总是下载的文件本身；
+    // 任何一步失败文件都会保留，只影响退出码
+    let post_process_failed = |download: &Download| {
+        let exec_failed = exec_failed(args.exec.as_deref(), download);
+        let extract_failed = args.extract
+            && extract_failed(
+                args.extract_to.as_deref(),
+                args.keep_archive,
+                download,
+                info,
+            );
+        exec_failed || extract_failed
+    };
+    // 按下 Ctrl-C 时取消所有下载：分片任务随之中止，.part 和续传记录保留，
+    // 输出到 stdout 时使用的临时文件会被删除
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
+            }
+        }
+    });
+    let progress = if args.quiet || args.json || args.no_progress {
+        Some(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()))
     } else {
-        println!("Server does not support range requests");
+        (args.urls.len() > 1).then(MultiProgress::new)
+    };
+    // 总进度条最先加入，显示在各文件进度条的上方；长度随各文件开始下载而增加
+    let total_progress = if args.no_progress {
+        // 不显示的总进度条只用来汇总进度，定时打印成一行文字
+        let bar = ProgressBar::with_draw_target(Some(0), ProgressDrawTarget::hidden());
+        if args.progress_interval > 0 {
+            tokio::spawn(print_progress(
+                bar.clone(),
+                Duration::from_secs(args.progress_interval),
+                stdout,
+            ));
+        }
+        Some(bar)
+    } else {
+        progress
+        .as_ref()
+        .filter(|_| args.total_progress && args.urls.len() > 1)
+        .map(|multi| {
+            let bar = multi.add(ProgressBar::new(0));
+            let template = args.progress_template.as_deref().unwrap_or("{spinner:.green} {prefix:24!} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} {bytes_per_sec}");
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(template)
+                    .unwrap()
+                    .progress_chars(args.progress_chars.as_deref().unwrap_or("#>-")),
+            );
+            bar.set_prefix(format!("total ({} files)", args.urls.len()));
+            bar
+        })
+    };
+    let mut opts = DownloadOptions {
+        output: args.output.filter(|_| !stdout),
+        max_chunks: args.max_chunks,
+        chunk_size: args.chunk_size,
+        max_retries: args.max_retries,
+        max_connections: args.max_connections,
+        adaptive: args.adaptive,
+        mirrors: args.mirrors,
+        sha256: args.sha256,
+        blake3: args.blake3,
+        size: None,
+        verify_sidecar: args.verify_sidecar,
+        fail_on_html: args.fail_on_html,
+        content_type: args.expect_content_type,
+        hash: args.print_hash,
+        headers: args.headers.into_iter().collect(),
+        user: args.user,
+        password,
+        bearer: args.bearer,
+        proxy: args.proxy,
+        limit_rate: args.limit_rate,
+        timeout: seconds(args.timeout),
+        connect_timeout: seconds(args.connect_timeout),
+        max_redirects: args.max_redirects,
+        ip_version: if args.ipv4 {
+            Some(IpVersion::V4)
+        } else if args.ipv6 {
+            Some(IpVersion::V6)
+        } else {
+            None
+        },
+        resolve: args.resolve,
+        insecure: args.insecure,
+        ca_certs: args.cacert,
+        client_cert: args.cert,
+        client_key: args.key,
+        force: args.force,
+        auto_rename: args.auto_rename,
+        preserve_unicode: args.preserve_unicode,
+        force_directories: args.force_directories,
+        cut_dirs: args.cut_dirs,
+        timestamp: !args.no_timestamp,
+        timestamping: args.timestamping,
+        temp_dir: args.temp_dir,
+        keep_temp: args.keep_temp,
+        space_check: !args.no_space_check,
+        events: None,
+        cancel: Some(cancel.clone()),
+        client: None,
+        host_clients: HashMap::new(),
+        progress: progress.clone(),
+        progress_template: args.progress_template.clone(),
+        progress_chars: args.progress_chars.clone(),
+        total_progress,
+        stdout,
+        discard: args.discard,
+        save_headers: args.save_headers,
+        dump_headers: args.dump_headers,
+        manifest: args.manifest,
+        user_agent: args.user_agent,
+        cookies: args.cookies,
+        load_cookies: args.load_cookies,
+        retry_wait: Duration::from_millis(args.retry_wait),
+        retry_passes: args.retry_passes,
+        quota: args.quota.map(|limit| Arc::new(Quota::new(limit))),
+        max_size: args.max_size,
+        // 脚本里没有人回答，只在终端上询问，除非用 --interactive 强制
+        confirm_size: (!args.yes && (args.interactive || io::stdin().is_terminal()))
+            .then_some(args.confirm_size),
+    };
+
+    // 即使在 --quiet 下也要提示，避免误用于生产环境
+    if opts.insecure {
+        eprintln!("WARNING: TLS certificate verification is disabled (--insecure), connections can be intercepted");
+    }
+
+    // 所有文件共用同一个客户端，复用连接池和 cookie
+    let jar = match idownloader::build_client_with_jar(&opts) {
+        Ok((client, jar)) => {
+            opts.client = Some(client);
+            jar
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return exit_code(&e);
+        }
+    };
+    // 保存 cookie 的守卫要在所有下载结束、main 返回时才写文件
+    let _save_cookies = args.save_cookies.take().map(|path| SaveCookies {
+        jar: jar.clone(),
+        path,
+    });
+
+    // .netrc 中的凭据只能发给对应的主机，有凭据的主机各用一个单独的客户端，
+    // 但都使用同一个 cookie 存储；每个请求按自己的地址选择客户端，
+    // 镜像和重定向后的其他主机不会收到凭据
+    let mut host_clients = HashMap::new();
+    if opts.user.is_none() && opts.bearer.is_none() {
+        let netrc = match load_netrc(args.netrc_file.as_deref()) {
+            Ok(netrc) => netrc,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let urls = args
+            .urls
+            .iter()
+            .chain(&opts.mirrors)
+            .chain(metalinks.values().flat_map(|file| &file.urls));
+        for url in urls {
+            let Some(host) = netrc_host(url) else {
+                continue;
+            };
+            if host_clients.contains_key(&host) {
+                continue;
+            }
+            let Some((login, password)) = netrc.credentials(&host) else {
+                continue;
+            };
+            let host_opts = DownloadOptions {
+                user: Some(login.to_string()),
+                password: password.map(str::to_string),
+                ..opts.clone()
+            };
+            match idownloader::build_client_sharing_jar(&host_opts, jar.clone()) {
+                Ok(client) => host_clients.insert(host, client),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    return exit_code(&e);
+                }
+            };
+        }
+    }
+    opts.host_clients = host_clients;
+
+    // Metalink 中的文件各自带有镜像、大小和校验值，清单中的文件带有分片方式
+    let opts_for = |url: &str| {
+        let file = metalinks.get(url);
+        let manifest = manifests.get(url);
+        if file.is_none() && manifest.is_none() {
+            return Cow::Borrowed(&opts);
+        }
+        let mut opts = opts.clone();
+        if let Some(manifest) = manifest {
+            manifest.apply(&mut opts);
+        }
+        if let Some(file) = file {
+            opts.mirrors = file.urls[1..].to_vec();
+            opts.size = file.size;
+            opts.sha256 = file.sha256.clone().or(opts.sha256);
+        }
+        Cow::Owned(opts)
+    };
+
+    let cancelled = || {
+        eprintln!("Download cancelled");
+        ExitCode::from(130)
+    };
+
+    if args.spider {
+        let results = stream::iter(&args.urls)
+            .map(|url| {
+                let opts = &opts;
+                async move { (url, idownloader::inspect(url, opts).await) }
+            })
+            .buffered(args.concurrent_files)
+            .collect::<Vec<_>>()
+            .await;
+        if cancel.is_cancelled() {
+            return cancelled();
+        }
+        for (url, result) in &results {
+            match result {
+                Ok(remote) => println!("  ok      {}: {}", url, describe_remote(remote)),
+                Err(e) => println!("  failed  {}: {}", url, e),
+            }
+        }
+        return batch_exit_code(results.iter().map(|(_, r)| r));
+    }
+
+    if args.dry_run {
+        let mut results = Vec::new();
+        for url in &args.urls {
+            let result = idownloader::plan(url, &opts_for(url)).await;
+            match &result {
+                Ok(plan) => print_plan(url, plan),
+                Err(_) if cancel.is_cancelled() => return cancelled(),
+                Err(e) => eprintln!("error: {}: {}", url, e),
+            }
+            results.push(result);
+        }
+        return batch_exit_code(results.iter());
+    }
+
+    if args.benchmark {
+        let mut results = Vec::new();
+        for url in &args.urls {
+            let result = idownloader::benchmark(url, &opts_for(url)).await;
+            match &result {
+                Ok(levels) => print_benchmark(url, levels),
+                Err(_) if cancel.is_cancelled() => return cancelled(),
+                Err(e) => eprintln!("error: {}: {}", url, e),
+            }
+            results.push(result);
+        }
+        return batch_exit_code(results.iter());
+    }
+
+    // 先测出每个文件最快的连接数，测不了的文件沿用默认设置
+    let mut tuned = HashMap::new();
+    if args.auto_tune {
+        for url in &args.urls {
+            match idownloader::benchmark(url, &opts_for(url)).await {
+                Ok(levels) => {
+                    if let Some(best) = idownloader::best_level(&levels) {
+                        info(format!(
+                            "Using {} connections for {} ({}/s)",
+                            best.connections,
+                            url,
+                            human_size(best.bytes_per_sec() as u64)
+                        ));
+                        tuned.insert(url.clone(), best.connections);
+                    }
+                }
+                Err(_) if cancel.is_cancelled() => return cancelled(),
+                Err(e) => info(format!("Cannot benchmark {}: {}", url, e)),
+            }
+        }
+    }
+    let opts_for = |url: &str| {
+        let mut opts = opts_for(url);
+        if let Some(&connections) = tuned.get(url) {
+            let opts = opts.to_mut();
+            opts.max_connections = connections;
+            opts.max_chunks = opts.max_chunks.max(connections as u64);
+        }
+        opts
+    };
+
+    if let [url] = args.urls.as_slice() {
+        let started = Instant::now();
+        let result = idownloader::download(url, &opts_for(url)).await;
+        if cancel.is_cancelled() {
+            return cancelled();
+        }
+        #[cfg(feature = "notify")]
+        if args.notify {
+            notify::finished(url, &result);
+        }
+        if args.json {
+            print_json(url, &result, started.elapsed());
+            if let Ok(download) = &result {
+                if post_process_failed(download) {
+                    return ExitCode::FAILURE;
+                }
+            }
+            return batch_exit_code([&result]);
+        }
+        return match result {
+            Ok(download) if download.skipped => {
+                info("File is up to date, skipping.".to_string());
+                print_hash(&download);
+                ExitCode::SUCCESS
+            }
+            Ok(download) => {
+                info("Download complete!".to_string());
+                let elapsed = started.elapsed();
+                if !stdout {
+                    let bytes = download.bytes;
+                    info(format!(
+                        "Downloaded {} in {:.2}s ({}/s)",
+                        human_size(bytes),
+                        elapsed.as_secs_f64(),
+                        human_size((bytes as f64 / elapsed.as_secs_f64()) as u64)
+                    ));
+                    if !args.discard {
+                        info(format!("File saved at: {}", download.path.display()));
+                    }
+                }
+                print_hash(&download);
+                if post_process_failed(&download) {
+                    return ExitCode::FAILURE;
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                for line in failed_chunk_lines(&e) {
+                    eprintln!("  {}", line);
+                }
+                exit_code(&e)
+            }
+        };
+    }
+
+    // 多个文件时某个失败不影响其他文件，最后统一汇总
+    let downloads = stream::iter(&args.urls)
+        .map(|url| {
+            let opts = opts_for(url);
+            async move {
+                let started = Instant::now();
+                let result = idownloader::download(url, &opts).await;
+                (url, result, started.elapsed())
+            }
+        })
+        .buffered(args.concurrent_files)
+        .collect::<Vec<_>>();
+    let results = downloads.await;
+    if let Some(total) = &opts.total_progress {
+        total.finish();
+    }
+    if cancel.is_cancelled() {
+        return cancelled();
+    }
+
+    let succeeded = results.iter().filter(|(_, r, _)| r.is_ok()).count();
+    #[cfg(feature = "notify")]
+    if args.notify {
+        let failure = results
+            .iter()
+            .find_map(|(url, r, _)| Some((url.as_str(), r.as_ref().err()?)));
+        notify::batch(succeeded, results.len(), failure);
+    }
+    for (url, result, elapsed) in results.iter().filter(|_| args.json) {
+        print_json(url, result, *elapsed);
+    }
+    info(format!(
+        "Downloaded {} of {} files:",
+        succeeded,
+        results.len()
+    ));
+    for (url, result, _) in &results {
+        match result {
+            Ok(download) if download.skipped => info(format!(
+                "  skipped {} -> {} (up to date)",
+                url,
+                download.path.display()
+            )),
+            Ok(download) if args.discard => info(format!(
+                "  ok      {} ({} discarded)",
+                url,
+                human_size(download.bytes)
+            )),
+            Ok(download) => info(format!("  ok      {} -> {}", url, download.path.display())),
+            Err(e) => {
+                info(format!("  failed  {}: {}", url, e));
+                for line in failed_chunk_lines(e) {
+                    info(format!("            {}", line));
+                }
+            }
+        }
     }
+    if !args.json {
+        for download in results.iter().filter_map(|(_, r, _)| r.as_ref().ok()) {
+            print_hash(download);
+        }
+    }
+    let mut hooks_failed = false;
+    for (_, result, _) in &results {
+        if let Ok(download) = result {
+            hooks_failed |= post_process_failed(download);
+        }
+    }
+    if hooks_failed && succeeded == results.len() {
+        return ExitCode::FAILURE;
+    }
+    batch_exit_code(results.iter().map(|(_, r, _)| r))
 }
 
-async fn download_chunk(
-    client: &reqwest::Client,
-    url: &str,
-    start: u64,
-    end: u64,
-    temp_path: &Path,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let response = client
-        .get(url)
-        .header(RANGE, format!("bytes={}-{}", start, end))
-        .send()
-        .await?;
-    let bytes = response.bytes().await?;
-    let mut file = File::create(temp_path).await?;
-    file.write_all(&bytes).await?;
-    Ok(bytes.to_vec())
+/// Runs the `--exec` command for a freshly downloaded file and reports
+/// whether it failed. Files that were already up to date are left alone.
+fn exec_failed(command: Option<&str>, download: &Download) -> bool {
+    let Some(command) = command.filter(|_| !download.skipped) else {
+        return false;
+    };
+    match run_exec(command, &download.path) {
+        Ok(status) if status.success() => false,
+        Ok(status) => {
+            eprintln!(
+                "error: `{}` failed for {}: {}",
+                command,
+                download.path.display(),
+                status
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("error: failed to run `{}`: {}", command, e);
+            true
+        }
+    }
+}
+
+/// Unpacks a freshly downloaded archive for `--extract` and reports whether
+/// that failed. Files that are not archives are left alone; an archive is
+/// only deleted once it has been unpacked completely.
+fn extract_failed(
+    extract_to: Option<&Path>,
+    keep_archive: bool,
+    download: &Download,
+    info: impl Fn(String),
+) -> bool {
+    if download.skipped {
+        return false;
+    }
+    let path = &download.path;
+    let format = match extract::Format::detect(path) {
+        Ok(Some(format)) => format,
+        Ok(None) => {
+            info(format!(
+                "{} is not an archive, not extracting",
+                path.display()
+            ));
+            return false;
+        }
+        Err(e) => {
+            eprintln!("error: failed to read {}: {}", path.display(), e);
+            return true;
+        }
+    };
+    let dest = extract_to.unwrap_or(path.parent().unwrap_or(Path::new(".")));
+    match extract::extract(path, format, dest) {
+        Ok(extracted) => {
+            info(format!(
+                "Extracted {} entries to {}",
+                extracted.entries,
+                dest.display()
+            ));
+            if extracted.skipped > 0 {
+                eprintln!(
+                    "warning: skipped {} entries of {} that would be written outside {}",
+                    extracted.skipped,
+                    path.display(),
+                    dest.display()
+                );
+            }
+            if !keep_archive {
+                if let Err(e) = fs::remove_file(path) {
+                    eprintln!("error: failed to delete {}: {}", path.display(), e);
+                    return true;
+                }
+            }
+            false
+        }
+        Err(e) => {
+            eprintln!("error: failed to extract {}: {}", path.display(), e);
+            true
+        }
+    }
+}
+
+/// Runs `command` through the shell with `{}` standing for `path`, or with
+/// `path` appended when there is no `{}`.
+fn run_exec(command: &str, path: &Path) -> io::Result<process::ExitStatus> {
+    // 路径作为参数传给 shell 而不是拼进命令里，文件名中的空格和引号不会被解释
+    #[cfg(unix)]
+    {
+        let script = if command.contains("{}") {
+            command.replace("{}", "\"$1\"")
+        } else {
+            format!("{} \"$1\"", command)
+        };
+        process::Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .arg("sh")
+            .arg(path)
+            .status()
+    }
+    // cmd 没有这样传参的办法，只能给路径加上引号拼进去
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        let quoted = format!("\"{}\"", path.display());
+        let script = if command.contains("{}") {
+            command.replace("{}", &quoted)
+        } else {
+            format!("{} {}", command, quoted)
+        };
+        process::Command::new("cmd")
+            .arg("/C")
+            .raw_arg(script)
+            .status()
+    }
+}
+
+/// Writes the cookie jar to `--save-cookies` when dropped, so the file is
+/// updated however `main` returns, including after a failed or cancelled
+/// download.
+struct SaveCookies {
+    jar: Arc<CookieJar>,
+    path: PathBuf,
+}
+
+impl Drop for SaveCookies {
+    fn drop(&mut self) {
+        if let Err(e) = self.jar.save_netscape(&self.path) {
+            eprintln!("error: failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Reads the `.netrc` file at `path`, or `~/.netrc` if it exists.
+fn load_netrc(path: Option<&Path>) -> Result<Netrc, String> {
+    if let Some(path) = path {
+        return Netrc::load(path).map_err(|e| format!("failed to read {}: {}", path.display(), e));
+    }
+    match dirs::home_dir().map(|home| Netrc::load(&home.join(".netrc"))) {
+        Some(Ok(netrc)) => Ok(netrc),
+        Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+            Err(format!("failed to read ~/.netrc: {}", e))
+        }
+        _ => Ok(Netrc::default()),
+    }
+}
+
+/// The host `.netrc` credentials are looked up for, unless the URL already
+/// carries its own.
+fn netrc_host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    if !url.username().is_empty() {
+        return None;
+    }
+    url.host_str().map(str::to_string)
+}
+
+/// Prints how far `bar` has got every `interval`, as `25% (100.00 MiB/400.00
+/// MiB)` or just the bytes so far while no size is known, until it finishes.
+async fn print_progress(bar: ProgressBar, interval: Duration, stdout: bool) {
+    let mut ticks = tokio::time::interval(interval);
+    // 第一次立即触发，这时还什么都没下载
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        if bar.is_finished() {
+            return;
+        }
+        let (position, length) = (bar.position(), bar.length().unwrap_or(0));
+        let line = match (position * 100).checked_div(length) {
+            Some(percent) => format!(
+                "{}% ({}/{})",
+                percent,
+                human_size(position),
+                human_size(length)
+            ),
+            None => human_size(position),
+        };
+        // 和其他提示信息一样，输出到 stdout 时改用 stderr
+        if stdout {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Exit status for a failed download, as listed under "退出码" in the readme.
+fn exit_code(e: &DownloadError) -> ExitCode {
+    ExitCode::from(match e {
+        DownloadError::Connect(_) => 2,
+        DownloadError::Request(e) if e.is_timeout() => 2,
+        DownloadError::ChecksumMismatch { .. } => 3,
+        e if e.is_disk_full() => 4,
+        DownloadError::Cancelled => 130,
+        _ => 1,
+    })
+}
+
+/// Success if every result is, otherwise the exit status of the first
+/// failure.
+fn batch_exit_code<'a, T: 'a>(
+    results: impl IntoIterator<Item = &'a Result<T, DownloadError>>,
+) -> ExitCode {
+    match results.into_iter().find_map(|r| r.as_ref().err()) {
+        Some(e) => exit_code(e),
+        None => ExitCode::SUCCESS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_must_be_positive_sizes() {
+        assert_eq!(parse_rate("500k"), Ok(500 << 10));
+        assert_eq!(parse_rate("2M"), Ok(2 << 20));
+        assert_eq!(parse_rate("100"), Ok(100));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("99999999999g").is_err());
+    }
+
+    #[test]
+    fn byte_limits_must_be_positive_sizes() {
+        assert_eq!(parse_byte_limit("1g"), Ok(1 << 30));
+        assert!(parse_byte_limit("0").is_err());
+        assert!(parse_byte_limit("99999999999g").is_err());
+    }
 }
@@ -0,0 +1,45 @@
+use crate::{DownloadError, DownloadOptions};
+use serde::{Deserialize, Serialize};
+
+/// A record of a finished download, saved as `<file>.manifest.json` when
+/// `DownloadOptions::manifest` is set, so it can be audited or repeated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// URL the download was started with
+    pub url: String,
+    /// URL after following redirects
+    pub final_url: String,
+    pub size: u64,
+    /// Number of chunks; 1 for a single stream and 0 for an empty file
+    pub chunks: u64,
+    /// Bytes per chunk, or the base size of equal chunks when `even`
+    pub chunk_size: u64,
+    /// Whether the file was split into equal chunks rather than chunks of
+    /// exactly `chunk_size` bytes
+    pub even: bool,
+    /// SHA-256 digest of the file, as hex
+    pub sha256: String,
+    /// Response headers of the probe request, as `Name: Value` lines
+    pub headers: Vec<String>,
+}
+
+impl Manifest {
+    /// Sets up `opts` to repeat the download: split the same way, and
+    /// verified against the recorded size and digest.
+    pub fn apply(&self, opts: &mut DownloadOptions) {
+        if self.even {
+            // 分片数不变时，均分的结果和上次完全相同
+            opts.chunk_size = None;
+            opts.max_chunks = self.chunks.max(1);
+        } else {
+            opts.chunk_size = Some(self.chunk_size);
+        }
+        opts.size = Some(self.size);
+        opts.sha256 = Some(self.sha256.clone());
+    }
+}
+
+/// Parses a manifest written by an earlier download.
+pub fn parse_manifest(json: &str) -> Result<Manifest, DownloadError> {
+    serde_json::from_str(json).map_err(|e| DownloadError::InvalidManifest(e.to_string()))
+}
@@ -0,0 +1,81 @@
+use crate::DownloadError;
+use roxmltree::{Document, Node};
+
+/// One file described by a Metalink document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetalinkFile {
+    /// Download URLs, most preferred first
+    pub urls: Vec<String>,
+    pub size: Option<u64>,
+    /// SHA-256 digest of the whole file, as hex
+    pub sha256: Option<String>,
+}
+
+/// Parses a Metalink 4 (`.meta4`, RFC 5854) or Metalink 3 (`.metalink`)
+/// document. Only HTTP(S) URLs are kept; files without any are skipped.
+pub fn parse_metalink(xml: &str) -> Result<Vec<MetalinkFile>, DownloadError> {
+    let doc = Document::parse(xml).map_err(|e| DownloadError::InvalidMetalink(e.to_string()))?;
+    let files: Vec<MetalinkFile> = doc
+        .descendants()
+        .filter(|n| is(n, "file"))
+        .filter_map(parse_file)
+        .collect();
+    if files.is_empty() {
+        return Err(DownloadError::InvalidMetalink(
+            "no file with an HTTP(S) URL".to_string(),
+        ));
+    }
+    Ok(files)
+}
+
+fn parse_file(file: Node) -> Option<MetalinkFile> {
+    let mut urls: Vec<(i64, String)> = file
+        .descendants()
+        .filter(|n| is(n, "url"))
+        .filter_map(|n| {
+            let url = n.text()?.trim();
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                return None;
+            }
+            // Metalink 4 的 priority 越小越优先，Metalink 3 的 preference 越大越优先
+            let rank = match (n.attribute("priority"), n.attribute("preference")) {
+                (Some(priority), _) => priority.parse().unwrap_or(999_999),
+                (None, Some(preference)) => -preference.parse::<i64>().unwrap_or(0),
+                (None, None) => 999_999,
+            };
+            Some((rank, url.to_string()))
+        })
+        .collect();
+    if urls.is_empty() {
+        return None;
+    }
+    // 稳定排序，优先级相同的保持文件中的顺序
+    urls.sort_by_key(|(rank, _)| *rank);
+
+    let size = file
+        .children()
+        .find(|n| is(n, "size"))
+        .and_then(|n| n.text()?.trim().parse().ok());
+    // 分块校验 <pieces> 里的 hash 不是整个文件的摘要，只看 <file> 或 <verification> 下的
+    let sha256 = file
+        .descendants()
+        .filter(|n| is(n, "hash"))
+        .filter(|n| {
+            n.parent()
+                .is_some_and(|p| is(&p, "file") || is(&p, "verification"))
+        })
+        .find(|n| matches!(n.attribute("type"), Some("sha-256" | "sha256")))
+        .and_then(|n| n.text())
+        .map(|hash| hash.trim().to_lowercase());
+
+    Some(MetalinkFile {
+        urls: urls.into_iter().map(|(_, url)| url).collect(),
+        size,
+        sha256,
+    })
+}
+
+/// Matches an element by local name, whatever namespace the document uses.
+fn is(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
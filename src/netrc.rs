@@ -0,0 +1,156 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Login credentials read from a `.netrc` file, as used by curl, wget and
+/// ftp.
+#[derive(Debug, Clone, Default)]
+pub struct Netrc {
+    machines: Vec<Machine>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Machine {
+    /// `None` for the `default` entry
+    host: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+impl Netrc {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parses the `machine`, `default`, `login` and `password` tokens.
+    /// `account` values and `macdef` macros are skipped, as are lines
+    /// starting with `#`.
+    pub fn parse(text: &str) -> Self {
+        let mut machines = Vec::new();
+        let mut current: Option<Machine> = None;
+        let mut lines = text.lines();
+        while let Some(line) = lines.next() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" | "default" => {
+                        machines.extend(current.take());
+                        let host = match token {
+                            "machine" => tokens.next().map(str::to_ascii_lowercase),
+                            _ => None,
+                        };
+                        current = Some(Machine {
+                            host,
+                            ..Machine::default()
+                        });
+                    }
+                    "login" => {
+                        let login = tokens.next().map(str::to_string);
+                        if let Some(machine) = &mut current {
+                            machine.login = login;
+                        }
+                    }
+                    "password" => {
+                        let password = tokens.next().map(str::to_string);
+                        if let Some(machine) = &mut current {
+                            machine.password = password;
+                        }
+                    }
+                    "account" => {
+                        tokens.next();
+                    }
+                    // 宏定义一直持续到下一个空行
+                    "macdef" => {
+                        for line in lines.by_ref() {
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        machines.extend(current);
+        Netrc { machines }
+    }
+
+    /// The login and password for `host`: the first `machine` entry naming
+    /// it, or else the `default` entry. Entries without a login are ignored.
+    pub fn credentials(&self, host: &str) -> Option<(&str, Option<&str>)> {
+        let host = host.to_ascii_lowercase();
+        let named = self
+            .machines
+            .iter()
+            .find(|m| m.host.as_deref() == Some(host.as_str()));
+        named
+            .or_else(|| self.machines.iter().find(|m| m.host.is_none()))
+            .and_then(|m| Some((m.login.as_deref()?, m.password.as_deref())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_entries_win_over_default() {
+        let netrc = Netrc::parse(
+            "machine example.com login alice password secret\n\
+             default login anonymous password guest\n",
+        );
+        assert_eq!(
+            netrc.credentials("example.com"),
+            Some(("alice", Some("secret")))
+        );
+        assert_eq!(
+            netrc.credentials("EXAMPLE.com"),
+            Some(("alice", Some("secret")))
+        );
+        assert_eq!(
+            netrc.credentials("other.org"),
+            Some(("anonymous", Some("guest")))
+        );
+    }
+
+    #[test]
+    fn entries_may_span_lines() {
+        let netrc = Netrc::parse(
+            "# comment\n\
+             machine a.example\n\
+             \tlogin bob\n\
+             \taccount ignored\n\
+             \tpassword pw\n\
+             machine b.example login carol\n",
+        );
+        assert_eq!(netrc.credentials("a.example"), Some(("bob", Some("pw"))));
+        assert_eq!(netrc.credentials("b.example"), Some(("carol", None)));
+        assert_eq!(netrc.credentials("c.example"), None);
+    }
+
+    #[test]
+    fn macros_are_skipped() {
+        let netrc = Netrc::parse(
+            "machine a.example login bob password pw\n\
+             macdef init\n\
+             machine evil.example login mallory password x\n\
+             cd /pub\n\
+             \n\
+             machine b.example login carol password pw2\n",
+        );
+        assert_eq!(netrc.credentials("a.example"), Some(("bob", Some("pw"))));
+        assert_eq!(netrc.credentials("evil.example"), None);
+        assert_eq!(netrc.credentials("b.example"), Some(("carol", Some("pw2"))));
+    }
+
+    #[test]
+    fn entries_without_a_login_are_ignored() {
+        let netrc = Netrc::parse("machine a.example password pw\n");
+        assert_eq!(netrc.credentials("a.example"), None);
+        assert_eq!(Netrc::parse("").credentials("a.example"), None);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::human_size;
+use idownloader::{Download, DownloadError};
+use notify_rust::Notification;
+
+/// Notifies the desktop that the download of `url` is over, with the file
+/// name and size on success or the error otherwise.
+pub fn finished(url: &str, result: &Result<Download, DownloadError>) {
+    match result {
+        Ok(download) => {
+            let name = download
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            send(
+                "Download complete",
+                &format!("{} ({})", name, human_size(download.bytes)),
+            );
+        }
+        Err(e) => send("Download failed", &format!("{}: {}", url, e)),
+    }
+}
+
+/// Notifies the desktop that a batch is over, naming the first failure if
+/// there was one.
+pub fn batch(succeeded: usize, total: usize, failure: Option<(&str, &DownloadError)>) {
+    let summary = format!("Downloaded {} of {} files", succeeded, total);
+    match failure {
+        Some((url, e)) => send(&summary, &format!("{}: {}", url, e)),
+        None => send(&summary, ""),
+    }
+}
+
+fn send(summary: &str, body: &str) {
+    let result = Notification::new()
+        .appname("iDownloader")
+        .summary(summary)
+        .body(body)
+        .show();
+    // 没有通知服务（例如在 SSH 会话里）时只给出警告，不影响退出码
+    if let Err(e) = result {
+        eprintln!("warning: failed to send desktop notification: {}", e);
+    }
+}
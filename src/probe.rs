@@ -0,0 +1,214 @@
+use crate::{local, DownloadError};
+use reqwest::header::{
+    HeaderMap, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    LAST_MODIFIED, RANGE,
+};
+use reqwest::StatusCode;
+use std::time::{Duration, SystemTime};
+
+/// What the server told us about a download before fetching it.
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    /// URL after following redirects, used for the actual download requests
+    pub url: String,
+    /// Status of the HEAD request, or of the ranged GET used instead
+    pub status: StatusCode,
+    pub content_length: Option<u64>,
+    pub accept_ranges: bool,
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    pub content_type: Option<String>,
+    /// Content-Encoding of a full GET, e.g. `gzip`. When set, the length and
+    /// ranges describe compressed bytes, so both are cleared and the file is
+    /// fetched in one decompressed stream.
+    pub content_encoding: Option<String>,
+    pub headers: HeaderMap,
+}
+
+impl RemoteFile {
+    /// The response headers as `Name: Value` lines, in the order received.
+    pub fn header_lines(&self) -> String {
+        self.headers
+            .iter()
+            .map(|(name, value)| {
+                format!("{}: {}\n", name, String::from_utf8_lossy(value.as_bytes()))
+            })
+            .collect()
+    }
+}
+
+/// Asks the server about `url`, following redirects. A HEAD request is tried
+/// first; servers that reject HEAD are probed with a one-byte ranged GET.
+/// `file://` URLs are answered from the local file system.
+pub async fn probe(
+    client: &reqwest::Client,
+    url: &str,
+    timeout: Option<Duration>,
+) -> Result<RemoteFile, DownloadError> {
+    if local::is_file_url(url) {
+        return local::probe(url);
+    }
+    let mut request = client.head(url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let response = match request.send().await {
+        Ok(response)
+            if matches!(
+                response.status(),
+                StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+            ) =>
+        {
+            return probe_with_get(client, url, timeout).await;
+        }
+        Ok(response) => response,
+        // 连不上主机时 GET 也不会成功
+        Err(e) if e.is_connect() => return Err(e.into()),
+        Err(_) => return probe_with_get(client, url, timeout).await,
+    };
+
+    if !response.status().is_success() {
+        return Err(DownloadError::Status(response.status()));
+    }
+    let headers = response.headers();
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.parse::<u64>()
+                .map_err(|_| DownloadError::InvalidContentLength(v.to_string()))
+        })
+        .transpose()?;
+    let accept_ranges = headers.get(ACCEPT_RANGES).and_then(|v| v.to_str().ok()) == Some("bytes");
+    // 有些服务器对 HEAD 总是返回长度 0，用 GET 确认文件是否真的为空
+    if content_length == Some(0) && accept_ranges {
+        return probe_with_get(client, url, timeout).await;
+    }
+    let content_encoding = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != "identity");
+
+    Ok(RemoteFile {
+        url: response.url().to_string(),
+        status: response.status(),
+        content_length: content_length.filter(|_| content_encoding.is_none()),
+        accept_ranges: accept_ranges && content_encoding.is_none(),
+        etag: etag(headers),
+        last_modified: last_modified(headers),
+        content_type: content_type(headers),
+        content_encoding,
+        headers: headers.clone(),
+    })
+}
+
+/// Requests the first byte of `url`. A 206 answer means ranges work and its
+/// Content-Range carries the full length, a 416 means the file is empty and
+/// a 200 means ranges are not supported. The body is never read.
+async fn probe_with_get(
+    client: &reqwest::Client,
+    url: &str,
+    timeout: Option<Duration>,
+) -> Result<RemoteFile, DownloadError> {
+    let mut request = client.get(url).header(RANGE, "bytes=0-0");
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let response = request.send().await?;
+
+    let headers = response.headers();
+    let (accept_ranges, content_length) = match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let total = content_range(headers).and_then(|(_, _, total)| total);
+            (total.is_some(), total)
+        }
+        // 连第一个字节都不存在，说明文件是空的
+        StatusCode::RANGE_NOT_SATISFIABLE => (true, Some(0)),
+        status if status.is_success() => {
+            let length = headers
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            (false, length)
+        }
+        status => return Err(DownloadError::Status(status)),
+    };
+
+    Ok(RemoteFile {
+        url: response.url().to_string(),
+        status: response.status(),
+        content_length,
+        accept_ranges,
+        etag: etag(headers),
+        last_modified: last_modified(headers),
+        content_type: content_type(headers),
+        // 带 Range 的请求不会协商压缩
+        content_encoding: None,
+        headers: headers.clone(),
+    })
+}
+
+fn last_modified(headers: &HeaderMap) -> Option<SystemTime> {
+    let value = headers.get(LAST_MODIFIED)?.to_str().ok()?;
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn etag(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Parses `Content-Range: bytes <start>-<end>/<total>` into its parts. The
+/// total is `None` when the server sent `*`.
+pub fn content_range(headers: &HeaderMap) -> Option<(u64, u64, Option<u64>)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    Some((
+        start.trim().parse().ok()?,
+        end.trim().parse().ok()?,
+        total.trim().parse().ok(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{serve, Response};
+    use crate::{build_client, extract_filename, DownloadOptions};
+
+    #[tokio::test]
+    async fn redirects_are_followed_to_the_final_url() {
+        let server = serve(|request| match request.path.as_str() {
+            "/download?id=7" => Response::new(302).header("Location", "/files/report.pdf"),
+            "/files/report.pdf" => Response::new(200)
+                .header("Accept-Ranges", "bytes")
+                .body("report"),
+            _ => Response::new(404),
+        })
+        .await;
+        let client = build_client(&DownloadOptions::default()).unwrap();
+        let remote = probe(&client, &format!("{}/download?id=7", server.url), None)
+            .await
+            .unwrap();
+        assert_eq!(remote.url, format!("{}/files/report.pdf", server.url));
+        assert_eq!(remote.content_length, Some(6));
+        assert!(remote.accept_ranges);
+        assert_eq!(extract_filename(&remote.url, &remote.headers), "report.pdf");
+        let requests = server.requests();
+        let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["/download?id=7", "/files/report.pdf"]);
+        assert!(requests.iter().all(|r| r.method == "HEAD"));
+        assert!(requests[1].header("user-agent").is_some());
+    }
+}
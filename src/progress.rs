@@ -0,0 +1,105 @@
+use indicatif::ProgressBar;
+use tokio::sync::mpsc::Sender;
+
+/// What a download reports through `DownloadOptions::events` as it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The transfer began; `total` is the file size when known
+    Started { total: Option<u64> },
+    /// `downloaded` bytes of the file have been received so far
+    Progress { downloaded: u64 },
+    /// Every byte has been received
+    Finished,
+}
+
+/// The progress bar of one download, mirrored to an optional event channel
+/// and an optional aggregate bar shared with other downloads.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    bar: ProgressBar,
+    total: Option<ProgressBar>,
+    events: Option<Sender<ProgressEvent>>,
+}
+
+impl Progress {
+    pub fn new(bar: ProgressBar, events: Option<Sender<ProgressEvent>>) -> Self {
+        let progress = Progress {
+            bar,
+            total: None,
+            events,
+        };
+        progress.emit(ProgressEvent::Started {
+            total: progress.bar.length(),
+        });
+        progress
+    }
+
+    /// Also counts this download's size and progress on `total`.
+    pub fn with_total(mut self, total: ProgressBar) -> Self {
+        total.inc_length(self.bar.length().unwrap_or(0));
+        total.inc(self.bar.position());
+        self.total = Some(total);
+        self
+    }
+
+    /// 进度事件只反映累计值，接收方处理不过来时丢掉中间的几次也没有关系
+    fn emit(&self, event: ProgressEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.try_send(event);
+        }
+    }
+
+    fn emit_position(&self) {
+        self.emit(ProgressEvent::Progress {
+            downloaded: self.bar.position(),
+        });
+    }
+
+    pub fn set_position(&self, pos: u64) {
+        if let Some(total) = &self.total {
+            let old = self.bar.position();
+            total.inc(pos.saturating_sub(old));
+            total.dec(old.saturating_sub(pos));
+        }
+        self.bar.set_position(pos);
+        // 续传时已完成的部分不应计入速度和剩余时间的估算
+        self.bar.reset_eta();
+        self.emit_position();
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.bar.inc(delta);
+        if let Some(total) = &self.total {
+            total.inc(delta);
+        }
+        self.emit_position();
+    }
+
+    pub fn dec(&self, delta: u64) {
+        self.bar.dec(delta);
+        if let Some(total) = &self.total {
+            total.dec(delta);
+        }
+        self.emit_position();
+    }
+
+    /// Marks the download as done. Unlike progress updates, the `Finished`
+    /// event waits for room in the channel so it is never lost.
+    pub async fn finish(&self) {
+        self.bar.finish();
+        if let Some(events) = &self.events {
+            let _ = events.send(ProgressEvent::Finished).await;
+        }
+    }
+
+    /// Stops drawing the bar after a failure; no `Finished` event is sent.
+    /// Its share of the aggregate bar is taken back off, since the download
+    /// either fails or starts over with a new bar.
+    pub fn abandon(&self) {
+        self.bar.abandon();
+        if let Some(total) = &self.total {
+            total.dec_length(self.bar.length().unwrap_or(0));
+            total.dec(self.bar.position());
+        }
+    }
+}
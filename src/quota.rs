@@ -0,0 +1,31 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Cap on the total number of bytes fetched, shared by every download that
+/// uses the same options so a whole batch stays within it.
+#[derive(Debug)]
+pub struct Quota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(limit: u64) -> Self {
+        Quota {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Records `bytes` as downloaded.
+    pub fn add(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.used.load(Ordering::Relaxed) >= self.limit
+    }
+}
@@ -0,0 +1,45 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::io;
+use std::net::SocketAddr;
+
+/// IP version to connect with, for hosts that have both kinds of address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolver that only hands out addresses of one IP version, so a broken
+/// IPv6 (or IPv4) route is never tried.
+pub struct FamilyResolver(pub IpVersion);
+
+impl Resolve for FamilyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let version = self.0;
+        Box::pin(async move {
+            // 端口由 reqwest 根据 URL 重新设置，这里填 0 即可
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| version.matches(addr))
+                .collect();
+            if addrs.is_empty() {
+                let family = match version {
+                    IpVersion::V4 => "IPv4",
+                    IpVersion::V6 => "IPv6",
+                };
+                let msg = format!("{} has no {} address", name.as_str(), family);
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// How a file of `content_length` bytes is split into `chunk_count` ranges.
+/// An even layout gives every chunk `chunk_size` bytes and one more to each
+/// of the first `content_length % chunk_count`; otherwise every chunk but
+/// the last, which is shorter, has exactly `chunk_size` bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkLayout {
+    pub content_length: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    pub even: bool,
+}
+
+impl ChunkLayout {
+    /// The layout of a file fetched in one stream, or of an empty file.
+    pub fn single(content_length: u64) -> Self {
+        ChunkLayout {
+            content_length,
+            chunk_size: content_length,
+            chunk_count: u64::from(content_length > 0),
+            even: true,
+        }
+    }
+
+    /// The inclusive byte range of chunk `index`.
+    pub fn range(&self, index: u64) -> (u64, u64) {
+        if self.even {
+            let remainder = self.content_length % self.chunk_count;
+            let start = index * self.chunk_size + index.min(remainder);
+            let len = self.chunk_size + u64::from(index < remainder);
+            (start, start + len - 1)
+        } else {
+            let start = index * self.chunk_size;
+            let end = if index == self.chunk_count - 1 {
+                self.content_length - 1
+            } else {
+                start + self.chunk_size - 1
+            };
+            (start, end)
+        }
+    }
+
+    /// The chunk holding byte `offset`, clamped to the last chunk.
+    pub fn index_at(&self, offset: u64) -> u64 {
+        let index = if self.even {
+            let remainder = self.content_length % self.chunk_count;
+            // 前 remainder 个分片各多一个字节
+            let long = remainder * (self.chunk_size + 1);
+            if offset < long {
+                offset / (self.chunk_size + 1)
+            } else {
+                remainder + (offset - long) / self.chunk_size.max(1)
+            }
+        } else {
+            offset / self.chunk_size.max(1)
+        };
+        index.min(self.chunk_count - 1)
+    }
+}
+
+/// Progress of a ranged download, persisted in the `.idm` sidecar next to the
+/// `.part` file so an interrupted run can pick up where it left off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeState {
+    pub url: String,
+    pub content_length: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    /// Whether the chunks follow the even layout; older `.idm` files, which
+    /// do not say, used the other one
+    pub even: bool,
+    pub etag: Option<String>,
+    /// Last-Modified of the file when the download started, as an HTTP date
+    pub last_modified: Option<String>,
+    pub completed: BTreeSet<u64>,
+}
+
+impl ResumeState {
+    pub fn new(
+        url: &str,
+        layout: ChunkLayout,
+        etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> Self {
+        ResumeState {
+            url: url.to_string(),
+            content_length: layout.content_length,
+            chunk_size: layout.chunk_size,
+            chunk_count: layout.chunk_count,
+            even: layout.even,
+            etag: etag.map(str::to_string),
+            last_modified: last_modified.map(httpdate::fmt_http_date),
+            completed: BTreeSet::new(),
+        }
+    }
+
+    pub fn layout(&self) -> ChunkLayout {
+        ChunkLayout {
+            content_length: self.content_length,
+            chunk_size: self.chunk_size,
+            chunk_count: self.chunk_count,
+            even: self.even,
+        }
+    }
+
+    /// Reads a sidecar file. Returns `None` if it is missing or malformed, in
+    /// which case the caller should start a fresh download.
+    pub fn load(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+        let mut url = None;
+        let mut content_length = None;
+        let mut chunk_size = None;
+        let mut chunk_count = None;
+        let mut even = false;
+        let mut etag = None;
+        let mut last_modified = None;
+        let mut completed = BTreeSet::new();
+
+        for line in text.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match key {
+                "url" => url = Some(value.to_string()),
+                "content_length" => content_length = value.parse().ok(),
+                "chunk_size" => chunk_size = value.parse().ok(),
+                "chunk_count" => chunk_count = value.parse().ok(),
+                "layout" => even = value == "even",
+                "etag" => etag = Some(value.to_string()),
+                "last_modified" => last_modified = Some(value.to_string()),
+                "completed" => {
+                    for index in value.split(',').filter(|s| !s.is_empty()) {
+                        completed.insert(index.parse().ok()?);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Some(ResumeState {
+            url: url?,
+            content_length: content_length?,
+            chunk_size: chunk_size?,
+            chunk_count: chunk_count?,
+            even,
+            etag,
+            last_modified,
+            completed,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let completed: Vec<String> = self.completed.iter().map(|i| i.to_string()).collect();
+        let mut text = format!(
+            "url={}\ncontent_length={}\nchunk_size={}\nchunk_count={}\n",
+            self.url, self.content_length, self.chunk_size, self.chunk_count
+        );
+        if self.even {
+            text.push_str("layout=even\n");
+        }
+        if let Some(etag) = &self.etag {
+            text.push_str(&format!("etag={}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            text.push_str(&format!("last_modified={}\n", last_modified));
+        }
+        text.push_str(&format!("completed={}\n", completed.join(",")));
+        fs::write(path, text)
+    }
+
+    /// 服务器上的文件没有变化时才能继续使用已下载的分片
+    pub fn matches(&self, url: &str, content_length: u64, etag: Option<&str>) -> bool {
+        self.url == url && self.content_length == content_length && self.etag.as_deref() == etag
+    }
+
+    /// The `If-Range` value for the version of the file the chunks so far
+    /// belong to. Weak ETags cannot be used there, so the Last-Modified date
+    /// stands in for them.
+    pub fn if_range(&self) -> Option<String> {
+        self.etag
+            .clone()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or_else(|| self.last_modified.clone())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed.len() as u64 == self.chunk_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that the chunks of `layout` cover every byte exactly once, in
+    /// order, and that `index_at` finds each chunk from its first and last
+    /// byte.
+    fn assert_covers(layout: ChunkLayout) {
+        let mut next = 0;
+        for i in 0..layout.chunk_count {
+            let (start, end) = layout.range(i);
+            assert_eq!(
+                start, next,
+                "gap or overlap before chunk {} of {:?}",
+                i, layout
+            );
+            assert!(end >= start, "empty chunk {} of {:?}", i, layout);
+            assert_eq!(layout.index_at(start), i, "{:?}", layout);
+            assert_eq!(layout.index_at(end), i, "{:?}", layout);
+            next = end + 1;
+        }
+        assert_eq!(next, layout.content_length, "{:?}", layout);
+    }
+
+    #[test]
+    fn even_layout_covers_the_file() {
+        for content_length in 1..=300 {
+            for chunk_count in 1..=content_length.min(40) {
+                let layout = ChunkLayout {
+                    content_length,
+                    chunk_size: content_length / chunk_count,
+                    chunk_count,
+                    even: true,
+                };
+                assert_covers(layout);
+                // 均分时各分片最多相差一个字节
+                let sizes: Vec<u64> = (0..chunk_count)
+                    .map(|i| {
+                        let (start, end) = layout.range(i);
+                        end - start + 1
+                    })
+                    .collect();
+                let min = sizes.iter().min().unwrap();
+                let max = sizes.iter().max().unwrap();
+                assert!(max - min <= 1, "{:?}: {:?}", layout, sizes);
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_layout_covers_the_file() {
+        for content_length in 1..=300 {
+            for chunk_size in 1..=50 {
+                assert_covers(ChunkLayout {
+                    content_length,
+                    chunk_size,
+                    chunk_count: content_length.div_ceil(chunk_size),
+                    even: false,
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn index_at_clamps_to_the_last_chunk() {
+        let layout = ChunkLayout {
+            content_length: 10,
+            chunk_size: 3,
+            chunk_count: 3,
+            even: true,
+        };
+        assert_eq!(layout.index_at(10), 2);
+        assert_eq!(layout.index_at(u64::MAX / 2), 2);
+    }
+
+    #[test]
+    fn single_layout() {
+        assert_eq!(ChunkLayout::single(0).chunk_count, 0);
+        let layout = ChunkLayout::single(5);
+        assert_eq!(layout.chunk_count, 1);
+        assert_covers(layout);
+    }
+
+    #[test]
+    fn state_round_trips_through_the_sidecar_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.idm");
+        let layout = ChunkLayout {
+            content_length: 1000,
+            chunk_size: 333,
+            chunk_count: 3,
+            even: true,
+        };
+        let mut state = ResumeState::new(
+            "https://example.com/file",
+            layout,
+            Some("\"abc\""),
+            Some(SystemTime::UNIX_EPOCH),
+        );
+        state.completed.insert(1);
+        state.save(&path).unwrap();
+        let loaded = ResumeState::load(&path).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.layout(), layout);
+        assert!(loaded.matches("https://example.com/file", 1000, Some("\"abc\"")));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::checksum::Algorithm;
+use crate::DownloadError;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Checksum file suffixes, tried in this order.
+const SUFFIXES: [&str; 3] = ["sha256", "sha1", "md5"];
+
+/// The digest a checksum file gives for a download.
+#[derive(Debug, Clone)]
+pub struct SidecarDigest {
+    pub algorithm: Algorithm,
+    /// Lowercase hex digest
+    pub hex: String,
+    /// Where the checksum file was found, for messages
+    pub location: String,
+}
+
+/// Finds the checksum file for `url` and reads the digest it gives for the
+/// download. `<file>.sha256`, `.sha1` and `.md5` next to the `local` file
+/// are tried first, then the same suffixes appended to `url`, which should
+/// be the final URL after redirects.
+pub async fn find_sidecar(
+    client: &reqwest::Client,
+    url: &str,
+    local: Option<&Path>,
+    timeout: Option<Duration>,
+) -> Result<SidecarDigest, DownloadError> {
+    let parsed = Url::parse(url).map_err(|_| DownloadError::SidecarNotFound(url.to_string()))?;
+    // 校验文件里记录的可能是服务器上的文件名，也可能是本地保存的文件名
+    let mut names: Vec<String> = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+        .into_iter()
+        .collect();
+    names.extend(
+        local
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned()),
+    );
+
+    let mut found = None;
+    if let Some(local) = local {
+        for suffix in SUFFIXES {
+            let mut path = PathBuf::from(local);
+            path.as_mut_os_string().push(format!(".{}", suffix));
+            if let Ok(text) = tokio::fs::read_to_string(&path).await {
+                found = Some((path.display().to_string(), text));
+                break;
+            }
+        }
+    }
+    if found.is_none() {
+        for suffix in SUFFIXES {
+            let mut sidecar_url = parsed.clone();
+            sidecar_url.set_path(&format!("{}.{}", parsed.path(), suffix));
+            let mut request = client.get(sidecar_url.clone());
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    found = Some((sidecar_url.to_string(), response.text().await?));
+                    break;
+                }
+                Ok(_) => continue,
+                Err(e) if e.is_connect() => return Err(e.into()),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    let (location, text) = found.ok_or_else(|| DownloadError::SidecarNotFound(url.to_string()))?;
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let hex = find_digest(&text, &names)
+        .ok_or_else(|| DownloadError::InvalidSidecar(location.clone()))?;
+    let algorithm = Algorithm::from_hex_len(hex.len())
+        .ok_or_else(|| DownloadError::InvalidSidecar(location.clone()))?;
+    Ok(SidecarDigest {
+        algorithm,
+        hex,
+        location,
+    })
+}
+
+/// Picks the digest for one of `names` out of a checksum file, written either
+/// as `HASH  name` (sha256sum and friends) or as `SHA256 (name) = HASH` (BSD).
+/// A file holding a single digest is taken to be about this download,
+/// whatever name it gives.
+fn find_digest(text: &str, names: &[&str]) -> Option<String> {
+    let entries: Vec<(&str, &str)> = text.lines().filter_map(parse_line).collect();
+    let hash = match entries.as_slice() {
+        [(hash, _)] => hash,
+        _ => &entries.iter().find(|(_, name)| names.contains(name))?.0,
+    };
+    Some(hash.to_ascii_lowercase())
+}
+
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (hash, name) = match line.rsplit_once(" = ") {
+        Some((left, hash)) if left.ends_with(')') => {
+            let (_, name) = left.split_once(" (")?;
+            (hash.trim(), &name[..name.len() - 1])
+        }
+        // `*` 表示以二进制模式计算，文件名前可能带有目录
+        _ => match line.split_once(char::is_whitespace) {
+            Some((hash, name)) => (hash, name.trim_start().trim_start_matches('*')),
+            None => (line, ""),
+        },
+    };
+    let name = name.rsplit('/').next().unwrap_or(name);
+    (!hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())).then_some((hash, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{serve, Response};
+    use crate::{download, DownloadOptions};
+
+    #[test]
+    fn gnu_lines() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            parse_line(&format!("{}  app.tar.gz", hash)),
+            Some((hash, "app.tar.gz"))
+        );
+        assert_eq!(
+            parse_line(&format!("{} *app.tar.gz", hash)),
+            Some((hash, "app.tar.gz"))
+        );
+        assert_eq!(
+            parse_line(&format!("{}  dist/app.tar.gz", hash)),
+            Some((hash, "app.tar.gz"))
+        );
+        assert_eq!(parse_line(hash), Some((hash, "")));
+    }
+
+    #[test]
+    fn bsd_lines() {
+        assert_eq!(
+            parse_line("SHA256 (app.tar.gz) = ABCDEF0123"),
+            Some(("ABCDEF0123", "app.tar.gz"))
+        );
+        assert_eq!(
+            parse_line("MD5 (my file (1).zip) = abc123"),
+            Some(("abc123", "my file (1).zip"))
+        );
+    }
+
+    #[test]
+    fn comments_and_junk_are_skipped() {
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(parse_line("# sha256sums"), None);
+        assert_eq!(parse_line("not a hash  app.tar.gz"), None);
+    }
+
+    #[test]
+    fn the_line_for_the_download_is_picked() {
+        let text = "\
+            # release checksums
+            aaaa  app-linux.tar.gz
+            BBBB *app-macos.tar.gz
+            SHA256 (app-windows.zip) = cccc
+        ";
+        assert_eq!(
+            find_digest(text, &["app-macos.tar.gz"]).as_deref(),
+            Some("bbbb")
+        );
+        assert_eq!(
+            find_digest(text, &["other", "app-windows.zip"]).as_deref(),
+            Some("cccc")
+        );
+        assert_eq!(find_digest(text, &["missing.tar.gz"]), None);
+        // 只有一行时不看文件名
+        assert_eq!(
+            find_digest("dddd  renamed.bin", &["app.bin"]).as_deref(),
+            Some("dddd")
+        );
+    }
+
+    #[tokio::test]
+    async fn the_checksum_file_is_looked_up_next_to_the_final_url() {
+        // "hello" 的 SHA-256
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let server = serve(move |request| match request.path.as_str() {
+            "/latest/app.bin" => Response::new(302).header("Location", "/assets/app-1.2.bin"),
+            "/assets/app-1.2.bin" => Response::new(200).body("hello"),
+            "/assets/app-1.2.bin.sha256" => {
+                Response::new(200).body(format!("{}  app-1.2.bin\n", digest))
+            }
+            _ => Response::new(404),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let opts = DownloadOptions {
+            output: Some(dir.path().to_path_buf()),
+            verify_sidecar: true,
+            ..DownloadOptions::default()
+        };
+        let download = download(&format!("{}/latest/app.bin", server.url), &opts)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&download.path).unwrap(), b"hello");
+        assert!(server
+            .requests()
+            .iter()
+            .all(|r| !r.path.starts_with("/latest/app.bin.")));
+    }
+}
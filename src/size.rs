@@ -0,0 +1,51 @@
+/// Parses a byte size such as `500k`, `2m` or `1g` (binary multiples). A bare
+/// number is taken as bytes.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last()?.to_ascii_lowercase() {
+        'k' => (&s[..s.len() - 1], 1 << 10),
+        'm' => (&s[..s.len() - 1], 1 << 20),
+        'g' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffixes_are_binary_multiples() {
+        assert_eq!(parse_size("500k"), Some(500 << 10));
+        assert_eq!(parse_size("500K"), Some(500 << 10));
+        assert_eq!(parse_size("2m"), Some(2 << 20));
+        assert_eq!(parse_size("2M"), Some(2 << 20));
+        assert_eq!(parse_size("1g"), Some(1 << 30));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size(" 4m "), Some(4 << 20));
+    }
+
+    #[test]
+    fn bare_numbers_are_bytes() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("1234"), Some(1234));
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        for s in [
+            "", " ", "k", "m", "abc", "1.5m", "-1k", "10 k", "5t", "1kb", "文件",
+        ] {
+            assert_eq!(parse_size(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn overflow_is_rejected() {
+        assert_eq!(parse_size("99999999999g"), None);
+        assert_eq!(parse_size("18446744073709551615k"), None);
+        assert_eq!(parse_size("18446744073709551616"), None);
+        assert_eq!(parse_size("18446744073709551615"), Some(u64::MAX));
+    }
+}
@@ -0,0 +1,168 @@
+//! A minimal HTTP/1.1 server on localhost for the tests. Every connection
+//! carries one request and is closed after the response.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// A request as the server received it.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// The value of header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The inclusive byte range of a `Range: bytes=a-b` header.
+    pub fn range(&self) -> Option<(u64, u64)> {
+        let (start, end) = self
+            .header("range")?
+            .strip_prefix("bytes=")?
+            .split_once('-')?;
+        Some((start.parse().ok()?, end.parse().ok()?))
+    }
+}
+
+/// The answer the handler gives to a request.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    content_length: bool,
+    delay: Duration,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            content_length: true,
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Leaves out Content-Length, so the body ends when the connection closes.
+    pub fn without_length(mut self) -> Self {
+        self.content_length = false;
+        self
+    }
+
+    /// Waits this long before answering.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// A 206 answer with `body[start..=end]` and a matching Content-Range.
+    pub fn partial(body: &[u8], start: u64, end: u64) -> Self {
+        Response::new(206)
+            .header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end, body.len()),
+            )
+            .body(&body[start as usize..=end as usize])
+    }
+}
+
+/// A running test server. The requests it has received are kept in order.
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Starts a server on a free port that answers every request with `handler`.
+/// It runs until the test's runtime shuts down.
+pub async fn serve<F>(handler: F) -> Server
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let handler = Arc::new(handler);
+    let log = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let handler = handler.clone();
+            let log = log.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let path = parts.next().unwrap_or_default().to_string();
+                let mut headers = Vec::new();
+                loop {
+                    line.clear();
+                    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let Some((name, value)) = line.trim_end().split_once(':') else {
+                        break;
+                    };
+                    headers.push((name.to_string(), value.trim().to_string()));
+                }
+                let request = Request {
+                    method,
+                    path,
+                    headers,
+                };
+                let response = handler(&request);
+                let head_only = request.method == "HEAD";
+                log.lock().unwrap().push(request);
+                tokio::time::sleep(response.delay).await;
+
+                let mut head =
+                    format!("HTTP/1.1 {} Test\r\nConnection: close\r\n", response.status);
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                if response.content_length {
+                    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+                }
+                head.push_str("\r\n");
+                let stream = stream.get_mut();
+                let _ = stream.write_all(head.as_bytes()).await;
+                if !head_only {
+                    let _ = stream.write_all(&response.body).await;
+                }
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    Server { url, requests }
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Instant};
+
+/// Token bucket shared by every chunk so the combined throughput stays under
+/// a fixed number of bytes per second.
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            rate: bytes_per_sec as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `bytes` may be written.
+    pub async fn acquire(&self, bytes: u64) {
+        // 持有锁等待，保证所有分片按顺序排队共享同一个速率
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.last = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-bucket.tokens / self.rate)).await;
+        }
+    }
+}